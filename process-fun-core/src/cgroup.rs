@@ -0,0 +1,393 @@
+//! Placement of children in per-call cgroup v2 subtrees, available with the `cgroup` feature.
+//!
+//! The child creates `process-fun-<pid>` below the parent cgroup while it is set up, writes its
+//! limits and moves itself in before the function runs, so everything it spawns is accounted
+//! and limited together. The parent derives the same path from the child's PID, reads the
+//! accounting of the cgroup once the child has been reaped, and removes the cgroup. Processes
+//! the child left behind in it are killed at that point.
+//!
+//! A cgroup other than the root can only enable controllers for its children while it contains
+//! no processes itself. If the calling process's own cgroup is used as the parent, the calling
+//! process is therefore moved into a `process-fun-parent` leaf below it the first time a
+//! controller has to be enabled, and the parent stays the same for later calls. Other processes
+//! in that cgroup are left alone, so they have to be moved out by whoever started them.
+
+use crate::{child, ProcessFunError};
+use nix::errno::Errno;
+use nix::unistd::{access, getpid, AccessFlags, Pid};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, thread};
+
+/// Attempts to remove the cgroup while the processes killed in it exit
+const REMOVE_ATTEMPTS: usize = 100;
+
+/// Name of the leaf the calling process is moved into when its own cgroup is the parent
+const LEAF_NAME: &str = "process-fun-parent";
+
+/// Limits of the cgroup a child is placed in.
+///
+/// The cgroup is created below `parent`, which defaults to the cgroup of the calling process.
+/// The controllers needed for the limits are enabled in `cgroup.subtree_control` of the parent
+/// if they are not already, which requires the parent to be delegated to the calling user. A
+/// parent other than the root cgroup must not contain processes itself for that. The calling
+/// process is moved out of the default parent into a `process-fun-parent` leaf below it, which
+/// is left in place, but any other processes in it, or in a given parent, are not touched and
+/// make enabling the controllers fail.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    pub(crate) parent: Option<PathBuf>,
+    pub(crate) memory_max: Option<u64>,
+    pub(crate) swap_max: Option<u64>,
+    pub(crate) cpu_max: Option<(Duration, Duration)>,
+    pub(crate) pids_max: Option<u64>,
+}
+
+impl CgroupLimits {
+    /// Create a cgroup without limits, which only accounts for the child
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the cgroup below `parent`, a directory of the cgroup v2 hierarchy
+    pub fn parent(mut self, parent: impl Into<PathBuf>) -> Self {
+        self.parent = Some(parent.into());
+        self
+    }
+
+    /// Limit the memory of the child and its descendants to `bytes` (`memory.max`). Exceeding
+    /// it invokes the OOM killer, which is reported as `ProcessFunError::OutOfMemory`, once
+    /// nothing more can be swapped out.
+    pub fn memory_max(mut self, bytes: u64) -> Self {
+        self.memory_max = Some(bytes);
+        self
+    }
+
+    /// Limit the swap used by the child and its descendants to `bytes` (`memory.swap.max`)
+    pub fn swap_max(mut self, bytes: u64) -> Self {
+        self.swap_max = Some(bytes);
+        self
+    }
+
+    /// Let the child and its descendants run for at most `quota` of CPU time in each `period`
+    /// (`cpu.max`)
+    pub fn cpu_max(mut self, quota: Duration, period: Duration) -> Self {
+        self.cpu_max = Some((quota, period));
+        self
+    }
+
+    /// Limit the number of processes and threads of the child and its descendants
+    /// (`pids.max`)
+    pub fn pids_max(mut self, count: u64) -> Self {
+        self.pids_max = Some(count);
+        self
+    }
+
+    /// Check that children can be placed in cgroups with these limits: the parent must be
+    /// writable and offer the controllers the limits need
+    pub fn check(&self) -> Result<(), ProcessFunError> {
+        let parent = parent_dir(self)?;
+        access(&parent, AccessFlags::W_OK).map_err(|e| {
+            ProcessFunError::ProcessError(format!(
+                "Cgroup {} is not writable: {}",
+                parent.display(),
+                e
+            ))
+        })?;
+        let path = parent.join("cgroup.controllers");
+        let available = fs::read_to_string(&path).map_err(|e| cgroup_error("read", &path, e))?;
+        match self
+            .controllers()
+            .find(|controller| !available.split_whitespace().any(|c| c == *controller))
+        {
+            Some(controller) => Err(ProcessFunError::ProcessError(format!(
+                "The {} controller is not available in {}",
+                controller,
+                parent.display()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The controllers needed to enforce the limits
+    fn controllers(&self) -> impl Iterator<Item = &'static str> {
+        [
+            self.memory_max.or(self.swap_max).map(|_| "memory"),
+            self.cpu_max.map(|_| "cpu"),
+            self.pids_max.map(|_| "pids"),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Accounting of the cgroup of a child, read once the child has finished
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CgroupStats {
+    /// Counters of `memory.events`, if the memory controller was enabled for the cgroup
+    pub memory_events: Option<MemoryEvents>,
+    /// Counters of `cpu.stat`
+    pub cpu: CpuStat,
+}
+
+impl CgroupStats {
+    /// Whether the OOM killer killed a process in the cgroup
+    pub fn oom_killed(&self) -> bool {
+        self.memory_events.is_some_and(|events| events.oom_kill > 0)
+    }
+}
+
+/// Counters of `memory.events`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryEvents {
+    /// Times the usage was reclaimed below `memory.low`
+    pub low: u64,
+    /// Times the usage was throttled above `memory.high`
+    pub high: u64,
+    /// Times the usage was about to exceed `memory.max`
+    pub max: u64,
+    /// Times the usage reached `memory.max` and allocations would have failed
+    pub oom: u64,
+    /// Processes killed by the OOM killer
+    pub oom_kill: u64,
+}
+
+/// Counters of `cpu.stat`. The throttling counters stay zero unless the cpu controller was
+/// enabled for the cgroup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuStat {
+    /// Total CPU time used
+    pub usage: Duration,
+    /// CPU time used in user mode
+    pub user: Duration,
+    /// CPU time used in kernel mode
+    pub system: Duration,
+    /// Periods of `cpu.max` that have elapsed
+    pub nr_periods: u64,
+    /// Periods in which the cgroup was throttled
+    pub nr_throttled: u64,
+    /// Total time the cgroup was throttled
+    pub throttled: Duration,
+}
+
+/// The cgroup of a child, removed when dropped
+#[derive(Debug)]
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// The cgroup the child `pid` places itself in according to `limits`
+    pub(crate) fn of_child(limits: &CgroupLimits, pid: Pid) -> Option<Self> {
+        let parent = parent_dir(limits).ok()?;
+        Some(Self {
+            path: child_path(&parent, pid),
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read the accounting of the cgroup. Counters that cannot be read are left at zero.
+    pub(crate) fn stats(&self) -> CgroupStats {
+        let memory_events = read_keyed(&self.path.join("memory.events")).map(|counters| {
+            let mut events = MemoryEvents::default();
+            for (key, value) in counters {
+                match key.as_str() {
+                    "low" => events.low = value,
+                    "high" => events.high = value,
+                    "max" => events.max = value,
+                    "oom" => events.oom = value,
+                    "oom_kill" => events.oom_kill = value,
+                    _ => {}
+                }
+            }
+            events
+        });
+
+        let mut cpu = CpuStat::default();
+        for (key, value) in read_keyed(&self.path.join("cpu.stat")).unwrap_or_default() {
+            match key.as_str() {
+                "usage_usec" => cpu.usage = Duration::from_micros(value),
+                "user_usec" => cpu.user = Duration::from_micros(value),
+                "system_usec" => cpu.system = Duration::from_micros(value),
+                "nr_periods" => cpu.nr_periods = value,
+                "nr_throttled" => cpu.nr_throttled = value,
+                "throttled_usec" => cpu.throttled = Duration::from_micros(value),
+                _ => {}
+            }
+        }
+
+        CgroupStats { memory_events, cpu }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Kill whatever the child left behind, so the cgroup can be removed
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..REMOVE_ATTEMPTS {
+            match fs::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(e) if e.kind() == ErrorKind::NotFound => return,
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+}
+
+/// Create the cgroup of the calling child according to `limits` and move the child into it
+pub(crate) fn enter(limits: &CgroupLimits) -> Result<(), ProcessFunError> {
+    let parent = parent_dir(limits)?;
+    enable_controllers(&parent, limits)?;
+
+    let path = child_path(&parent, getpid());
+    if let Err(e) = fs::create_dir(&path) {
+        // Left behind by a process with the same PID whose wrapper was leaked
+        if e.kind() != ErrorKind::AlreadyExists || fs::remove_dir(&path).is_err() {
+            return Err(cgroup_error("create", &path, e));
+        }
+        fs::create_dir(&path).map_err(|e| cgroup_error("create", &path, e))?;
+    }
+
+    if let Some(bytes) = limits.memory_max {
+        write_file(&path, "memory.max", bytes.to_string())?;
+    }
+    if let Some(bytes) = limits.swap_max {
+        write_file(&path, "memory.swap.max", bytes.to_string())?;
+    }
+    if let Some((quota, period)) = limits.cpu_max {
+        write_file(
+            &path,
+            "cpu.max",
+            format!("{} {}", quota.as_micros(), period.as_micros()),
+        )?;
+    }
+    if let Some(count) = limits.pids_max {
+        write_file(&path, "pids.max", count.to_string())?;
+    }
+
+    write_file(&path, "cgroup.procs", "0".to_string())
+}
+
+/// Enable the controllers needed for `limits` for the children of `parent`
+fn enable_controllers(parent: &Path, limits: &CgroupLimits) -> Result<(), ProcessFunError> {
+    let subtree_control = parent.join("cgroup.subtree_control");
+    let enabled = fs::read_to_string(&subtree_control)
+        .map_err(|e| cgroup_error("read", &subtree_control, e))?;
+    for controller in limits.controllers() {
+        if enabled.split_whitespace().any(|c| c == controller) {
+            continue;
+        }
+        let enable = || fs::write(&subtree_control, format!("+{}", controller));
+        let result = match enable() {
+            // The parent contains processes, such as the caller if it is its own cgroup
+            Err(e) if e.raw_os_error() == Some(Errno::EBUSY as i32) && limits.parent.is_none() => {
+                move_to_leaf(parent)?;
+                enable()
+            }
+            result => result,
+        };
+        result.map_err(|e| {
+            let reason = if e.raw_os_error() == Some(Errno::EBUSY as i32) {
+                "it contains other processes".to_string()
+            } else {
+                e.to_string()
+            };
+            ProcessFunError::ProcessError(format!(
+                "Failed to enable the {} controller in {}: {}",
+                controller,
+                parent.display(),
+                reason
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Move the process that called the process function into the leaf of `parent`, along with
+/// the calling child, which still is in the caller's cgroup
+fn move_to_leaf(parent: &Path) -> Result<(), ProcessFunError> {
+    let leaf = parent.join(LEAF_NAME);
+    match fs::create_dir(&leaf) {
+        Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+            return Err(cgroup_error("create", &leaf, e))
+        }
+        _ => {}
+    }
+
+    let procs = leaf.join("cgroup.procs");
+    for pid in [child::parent_pid(), getpid()] {
+        fs::write(&procs, pid.to_string()).map_err(|e| cgroup_error("write", &procs, e))?;
+    }
+    Ok(())
+}
+
+/// The directory the cgroup of a child is created in
+fn parent_dir(limits: &CgroupLimits) -> Result<PathBuf, ProcessFunError> {
+    match &limits.parent {
+        Some(parent) => Ok(parent.clone()),
+        // The calling process may have been moved into the leaf by an earlier call
+        None => current_cgroup().map(|own| match own.parent() {
+            Some(parent) if own.ends_with(LEAF_NAME) => parent.to_path_buf(),
+            _ => own,
+        }),
+    }
+}
+
+fn child_path(parent: &Path, pid: Pid) -> PathBuf {
+    parent.join(format!("process-fun-{}", pid))
+}
+
+/// The directory of the cgroup v2 the calling process belongs to
+fn current_cgroup() -> Result<PathBuf, ProcessFunError> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    let cgroup = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| {
+            ProcessFunError::ProcessError("Process is not in a cgroup v2 hierarchy".to_string())
+        })?;
+
+    // Fields of a mount are followed by " - " and the file system type
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split_once(" - "))
+        .filter(|(_, fs_type)| fs_type.starts_with("cgroup2 "))
+        .find_map(|(mount, _)| {
+            let mut fields = mount.split_whitespace().skip(3);
+            let root = fields.next()?;
+            let mount_point = fields.next()?;
+            let relative = Path::new(cgroup).strip_prefix(root).ok()?;
+            Some(Path::new(mount_point).join(relative))
+        })
+        .ok_or_else(|| {
+            ProcessFunError::ProcessError("The cgroup v2 hierarchy is not mounted".to_string())
+        })
+}
+
+fn write_file(cgroup: &Path, name: &str, value: String) -> Result<(), ProcessFunError> {
+    let path = cgroup.join(name);
+    fs::write(&path, value).map_err(|e| cgroup_error("write", &path, e))
+}
+
+/// Read a file of `key value` lines, such as `cpu.stat`
+fn read_keyed(path: &Path) -> Option<Vec<(String, u64)>> {
+    let text = fs::read_to_string(path).ok()?;
+    Some(
+        text.lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                Some((key.to_string(), value.trim().parse().ok()?))
+            })
+            .collect(),
+    )
+}
+
+fn cgroup_error(action: &str, path: &Path, e: std::io::Error) -> ProcessFunError {
+    ProcessFunError::ProcessError(format!("Failed to {} {}: {}", action, path.display(), e))
+}
@@ -0,0 +1,182 @@
+//! Bidirectional typed channels between a process function and its parent.
+
+use crate::wire::{self, FrameDecoder, FrameKind};
+use crate::{poll_timeout, CodecKind, ProcessFunError, ProcessOptions};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Maximal number of bytes read from the socket at once
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Socket of the channel of the process function running in this child, or -1
+static CHILD_CHANNEL: AtomicI32 = AtomicI32::new(-1);
+
+/// One end of a channel between a process function and its parent, receiving messages of
+/// type `R` and sending messages of type `S`.
+///
+/// A function taking a `ProcessChannel<R, S>` parameter gets its end in the child. Its
+/// `_process` variant does not take the parameter and returns the parent's end, a
+/// `ProcessChannel<S, R>`, next to the `ProcessWrapper`. Messages can be exchanged until
+/// either end is dropped, which happens in the child when the function returns.
+///
+/// Sending blocks while the buffer of the channel is full, so the other end must keep
+/// receiving.
+#[derive(Debug)]
+pub struct ProcessChannel<R, S> {
+    stream: UnixStream,
+    decoder: FrameDecoder,
+    codec: CodecKind,
+    closed: bool,
+    _ghost: PhantomData<fn(S) -> R>,
+}
+
+impl<R, S> ProcessChannel<R, S> {
+    /// Create both ends of a channel whose messages are serialized with the default codec
+    pub fn pair() -> Result<(Self, ProcessChannel<S, R>), ProcessFunError> {
+        Self::pair_with_options(&ProcessOptions::default())
+    }
+
+    /// Create both ends of a channel for a process function started with `options`
+    pub fn pair_with_options(
+        options: &ProcessOptions,
+    ) -> Result<(Self, ProcessChannel<S, R>), ProcessFunError> {
+        let codec = options.codec_kind();
+        let (this, other) = UnixStream::pair()?;
+        Ok((
+            Self::from_stream(this, codec)?,
+            ProcessChannel::from_stream(other, codec)?,
+        ))
+    }
+
+    /// Use this end for the process function running in this child, which lets it send and
+    /// receive messages under a seccomp filter. Must be called in the child after
+    /// `start_child`.
+    pub fn use_in_child(&self) {
+        CHILD_CHANNEL.store(self.stream.as_raw_fd(), Ordering::SeqCst);
+    }
+
+    fn from_stream(mut stream: UnixStream, codec: CodecKind) -> Result<Self, ProcessFunError> {
+        stream.write_all(&wire::stream_header(codec))?;
+        Ok(Self {
+            stream,
+            decoder: FrameDecoder::without_start().expect_codec(codec),
+            codec,
+            closed: false,
+            _ghost: PhantomData,
+        })
+    }
+}
+
+/// The socket of the channel of the process function running in this child
+pub(crate) fn child_channel() -> Option<RawFd> {
+    Some(CHILD_CHANNEL.load(Ordering::SeqCst)).filter(|&fd| fd >= 0)
+}
+
+/// Forget the channel of an outer process function in a nested child
+pub(crate) fn forget_child_channel() {
+    CHILD_CHANNEL.store(-1, Ordering::SeqCst);
+}
+
+impl<R, S> ProcessChannel<R, S>
+where
+    S: Serialize,
+{
+    /// Send a message to the other end
+    pub fn send(&mut self, message: &S) -> Result<(), ProcessFunError> {
+        let payload = self.codec.encode(message)?;
+        let header = wire::frame_header(FrameKind::Message, payload.len())?;
+        self.stream
+            .write_all(&header)
+            .and_then(|_| self.stream.write_all(&payload))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset => {
+                    ProcessFunError::ChannelClosed
+                }
+                _ => ProcessFunError::from(e),
+            })
+    }
+}
+
+impl<R, S> ProcessChannel<R, S>
+where
+    R: DeserializeOwned,
+{
+    /// Block until a message arrives.
+    ///
+    /// Fails with `ProcessFunError::ChannelClosed` once the other end was dropped and all
+    /// messages it sent have been received.
+    pub fn recv(&mut self) -> Result<R, ProcessFunError> {
+        loop {
+            if let Some(message) = self.recv_until(None)? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Receive a message if one has arrived, without blocking
+    pub fn try_recv(&mut self) -> Result<Option<R>, ProcessFunError> {
+        self.recv_until(Some(Instant::now()))
+    }
+
+    /// Wait at most `timeout` for a message to arrive
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<R>, ProcessFunError> {
+        self.recv_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Receive a message, waiting until `deadline` if given
+    fn recv_until(&mut self, deadline: Option<Instant>) -> Result<Option<R>, ProcessFunError> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                if frame.kind != FrameKind::Message {
+                    return Err(ProcessFunError::ProtocolError(format!(
+                        "unexpected {:?} frame on channel",
+                        frame.kind
+                    )));
+                }
+                return Ok(Some(self.codec.decode(&frame.payload)?));
+            }
+            if self.closed {
+                self.decoder.finish()?;
+                return Err(ProcessFunError::ChannelClosed);
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => poll_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => PollTimeout::NONE,
+            };
+            match poll(
+                &mut [PollFd::new(self.stream.as_fd(), PollFlags::POLLIN)],
+                timeout,
+            ) {
+                Ok(0) => return Ok(None),
+                Ok(_) => self.read_chunk()?,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    return Err(ProcessFunError::ProcessError(format!(
+                        "Failed to poll channel: {}",
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<(), ProcessFunError> {
+        match self.decoder.read_from(&mut &self.stream, READ_CHUNK_SIZE) {
+            Ok(0) => self.closed = true,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => self.closed = true,
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Setup performed in the child right after it is forked, before the function runs, and
+//! reporting its outcome once it has.
+
+use crate::wire::FrameWriter;
+use crate::{
+    channel, landlock, namespaces, privileges, seccomp, shared_memory, ProcessFunError,
+    ProcessGroup, ProcessOptions, ResourceLimits,
+};
+use interprocess::unnamed_pipe::Sender;
+use nix::sys::prctl;
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{getpid, getppid, setpgid, setsid, Pid};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// Set in children created by process-fun, and inherited by their own children
+static IN_PROCESS_FUNCTION: AtomicBool = AtomicBool::new(false);
+
+/// Process id of the parent, recorded right before forking
+static PARENT_PID: AtomicI32 = AtomicI32::new(0);
+
+/// Writer of the frames sent to the parent, set once the child has sent its start time
+static WRITER: Mutex<Option<FrameWriter<Sender>>> = Mutex::new(None);
+
+/// Record the current process as the parent of the child about to be forked
+pub(crate) fn record_parent() {
+    PARENT_PID.store(getpid().as_raw(), Ordering::SeqCst);
+}
+
+/// Process id of the process that called the process function running in this child
+#[cfg(feature = "cgroup")]
+pub(crate) fn parent_pid() -> Pid {
+    Pid::from_raw(PARENT_PID.load(Ordering::SeqCst))
+}
+
+/// Whether the current process is running a process function
+pub fn in_process_function() -> bool {
+    IN_PROCESS_FUNCTION.load(Ordering::SeqCst)
+}
+
+/// Prepare the child process according to `options`.
+///
+/// Must be called in the child right after `fork_process`, before the start time is sent.
+/// The parent reads the start time before it signals the child, so the child's process group
+/// is guaranteed to exist by then.
+pub fn setup_child(options: &ProcessOptions) -> Result<(), ProcessFunError> {
+    #[cfg(feature = "debug")]
+    eprintln!(
+        "[process-fun-debug] Setting up child process: {:?}",
+        options
+    );
+
+    let leads_process_group = options.leads_process_group();
+    if leads_process_group {
+        match options.process_group {
+            ProcessGroup::Session => setsid().map(drop),
+            _ => setpgid(Pid::from_raw(0), Pid::from_raw(0)),
+        }
+        .map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to create process group: {}", e))
+        })?;
+    }
+
+    #[cfg(feature = "cgroup")]
+    if let Some(limits) = &options.cgroup {
+        crate::cgroup::enter(limits)?;
+    }
+
+    // Before any thread is started, as a user namespace can only be entered by a single thread
+    namespaces::unshare_namespaces(&options.namespaces)?;
+
+    // Also before any thread is started, as changing the environment is not thread-safe
+    apply_environment(options)?;
+
+    if let Some(signal) = options.parent_death_signal {
+        prctl::set_pdeathsig(signal).map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to set parent-death signal: {}", e))
+        })?;
+
+        // The parent may have died before the parent-death signal was set
+        if getppid().as_raw() != PARENT_PID.load(Ordering::SeqCst) {
+            #[cfg(feature = "debug")]
+            eprintln!("[process-fun-debug] Parent died before child setup, exiting");
+
+            let _ = kill(getpid(), signal);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(deadline) = options.deadline {
+        start_deadline(deadline, leads_process_group)?;
+    }
+
+    IN_PROCESS_FUNCTION.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Set the environment, current directory and umask of the child
+fn apply_environment(options: &ProcessOptions) -> Result<(), ProcessFunError> {
+    if options.env_clear {
+        for (key, _) in std::env::vars_os() {
+            std::env::remove_var(key);
+        }
+    }
+    for (key, value) in &options.env {
+        match value {
+            Some(value) => std::env::set_var(key, value),
+            None => std::env::remove_var(key),
+        }
+    }
+
+    if let Some(dir) = &options.current_dir {
+        std::env::set_current_dir(dir).map_err(|e| {
+            ProcessFunError::ProcessError(format!(
+                "Failed to change directory to {}: {}",
+                dir.display(),
+                e
+            ))
+        })?;
+    }
+
+    if let Some(mask) = options.umask {
+        umask(Mode::from_bits_truncate(mask as nix::libc::mode_t));
+    }
+    Ok(())
+}
+
+/// Restrict what the child may do according to `options`.
+///
+/// Must be called in the child after `start_child`, so the restrictions cannot interfere
+/// with the handshake.
+pub fn restrict_child(options: &ProcessOptions) -> Result<(), ProcessFunError> {
+    if options.namespaces.pid {
+        if let Some(function) = namespaces::fork_pid_namespace(&options.namespaces)? {
+            // The outcome is sent by the function's process, which exits before this one
+            drop(lock_writer().take());
+            namespaces::mirror_exit(function);
+        }
+    }
+
+    apply_limits(&options.limits)?;
+    privileges::drop_privileges(&options.privileges)?;
+    landlock::restrict(&options.fs_access)?;
+
+    // Installed last, as it may forbid the system calls needed for the other restrictions
+    if let Some(filter) = &options.seccomp {
+        let pipe = lock_writer()
+            .as_ref()
+            .map(|writer| writer.get_ref().as_raw_fd())
+            .ok_or_else(|| {
+                ProcessFunError::ProcessError("Child has not been started".to_string())
+            })?;
+        let fds: Vec<_> = [shared_memory::child_memfd(), channel::child_channel()]
+            .into_iter()
+            .flatten()
+            .collect();
+        seccomp::install(filter, pipe, &fds)?;
+    }
+    Ok(())
+}
+
+fn apply_limits(limits: &ResourceLimits) -> Result<(), ProcessFunError> {
+    let set = |resource: Resource, soft: u64, hard: u64| {
+        setrlimit(resource, soft, hard).map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to set {:?}: {}", resource, e))
+        })
+    };
+
+    if let Some(bytes) = limits.address_space {
+        set(Resource::RLIMIT_AS, bytes, bytes)?;
+    }
+    if let Some(cpu_time) = limits.cpu_time {
+        // SIGXCPU is sent at the soft limit. A child handling it is killed a second later.
+        let seconds = cpu_time.as_secs() + u64::from(cpu_time.subsec_nanos() > 0);
+        set(Resource::RLIMIT_CPU, seconds, seconds + 1)?;
+    }
+    if let Some(count) = limits.nofile {
+        set(Resource::RLIMIT_NOFILE, count, count)?;
+    }
+    if let Some(bytes) = limits.file_size {
+        set(Resource::RLIMIT_FSIZE, bytes, bytes)?;
+    }
+    if let Some(count) = limits.nproc {
+        set(Resource::RLIMIT_NPROC, count, count)?;
+    }
+    Ok(())
+}
+
+/// Send the stream header and start time to the parent, telling it that the child is set up.
+///
+/// Must be called in the child after `setup_child`. Frames sent by the function while it runs,
+/// e.g. progress updates, go through `pipe` as well, serialized with the codec of `options`.
+pub fn start_child(pipe: Sender, options: &ProcessOptions) -> Result<(), ProcessFunError> {
+    let writer = FrameWriter::start_with_codec(pipe, options.codec_kind())?;
+    // Replaces the writer and channel a nested process function inherited from its parent
+    *lock_writer() = Some(writer);
+    channel::forget_child_channel();
+    Ok(())
+}
+
+fn lock_writer() -> MutexGuard<'static, Option<FrameWriter<Sender>>> {
+    // A frame interrupted by a panic breaks the stream either way
+    WRITER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Write frames to the parent. Nothing is written unless a process function is running in
+/// the current process.
+pub(crate) fn with_writer(
+    f: impl FnOnce(&mut FrameWriter<Sender>) -> Result<(), ProcessFunError>,
+) -> Result<(), ProcessFunError> {
+    match lock_writer().as_mut() {
+        Some(writer) => f(writer),
+        None => Ok(()),
+    }
+}
+
+/// Send each item of a streaming process function to the parent as soon as it is produced
+pub fn send_items<I>(items: I) -> Result<(), ProcessFunError>
+where
+    I: IntoIterator,
+    I::Item: serde::Serialize,
+{
+    for item in items {
+        with_writer(|writer| writer.write_item(&item))?;
+    }
+    Ok(())
+}
+
+/// Send the outcome of the function to the parent and exit the child.
+///
+/// The child must never return into the caller's code, so failures to report the outcome
+/// only affect the exit code.
+pub fn finish_child<T: serde::Serialize>(outcome: Result<T, ProcessFunError>) -> ! {
+    #[cfg(feature = "debug")]
+    eprintln!(
+        "[process-fun-debug] Child process outcome is ok: {}",
+        outcome.is_ok()
+    );
+
+    let Some(mut writer) = lock_writer().take() else {
+        std::process::exit(1);
+    };
+    let exit_code = match shared_memory::write_outcome(&mut writer, &outcome) {
+        Ok(()) if outcome.is_ok() => 0,
+        Ok(()) => 101,
+        // Nothing was written if the value could not be serialized, so report that instead
+        Err(error @ ProcessFunError::SerError(_)) => {
+            let _ = writer.write_outcome::<()>(&Err(error));
+            101
+        }
+        Err(_) => 1,
+    };
+
+    // Close the pipe before exiting, as `exit` does not run destructors
+    drop(writer);
+    std::process::exit(exit_code)
+}
+
+/// Kill the child once `deadline` has passed, using a watchdog thread so that the function
+/// body cannot interfere by handling or blocking signals
+fn start_deadline(deadline: Duration, leads_process_group: bool) -> Result<(), ProcessFunError> {
+    let (started, running) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("process-fun-deadline".to_string())
+        .spawn(move || {
+            let _ = started.send(());
+            std::thread::sleep(deadline);
+
+            #[cfg(feature = "debug")]
+            eprintln!("[process-fun-debug] Child deadline exceeded, killing child");
+
+            if leads_process_group {
+                let _ = killpg(getpid(), Signal::SIGKILL);
+            }
+            let _ = kill(getpid(), Signal::SIGKILL);
+        })
+        .map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to start deadline watchdog: {}", e))
+        })?;
+
+    // A seccomp filter installed while the thread is still starting would kill the child
+    let _ = running.recv();
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Serialization formats for the values exchanged with children.
+//!
+//! Results, errors, progress updates, items and channel messages are serialized with the codec
+//! selected for the call, bincode unless another one is chosen with `ProcessOptions::codec` or
+//! `set_default`. Codecs other than bincode are enabled by the feature of the same name. The
+//! codec is recorded in the header of each stream, so a stream written with a different codec
+//! than the receiver expects is reported as a protocol error instead of garbage.
+
+use crate::ProcessFunError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Codec used by calls that do not select one, as the id of a `CodecKind`
+static DEFAULT_CODEC: AtomicU8 = AtomicU8::new(CodecKind::Bincode as u8);
+
+/// A serialization format for the values exchanged with children
+pub trait Codec {
+    /// The kind selecting this codec at runtime
+    const KIND: CodecKind;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError>;
+
+    /// Serialize `value` into `writer` without collecting it in memory first
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError>;
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError>;
+}
+
+/// bincode 1.x, compact and fast, but unable to handle types that need `deserialize_any`
+/// such as `#[serde(flatten)]` or `serde_json::Value`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    const KIND: CodecKind = CodecKind::Bincode;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError> {
+        Ok(bincode::serialize_into(writer, value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// postcard, even more compact than bincode with the same limitations
+#[cfg(feature = "postcard")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    const KIND: CodecKind = CodecKind::Postcard;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        postcard::to_stdvec(value).map_err(ser_error)
+    }
+
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError> {
+        postcard::to_io(value, writer).map(drop).map_err(ser_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError> {
+        postcard::from_bytes(bytes).map_err(ser_error)
+    }
+}
+
+/// MessagePack, a self-describing binary format. Structs are encoded as maps.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    const KIND: CodecKind = CodecKind::MessagePack;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        rmp_serde::to_vec_named(value).map_err(ser_error)
+    }
+
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        mut writer: W,
+    ) -> Result<(), ProcessFunError> {
+        rmp_serde::encode::write_named(&mut writer, value).map_err(ser_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError> {
+        rmp_serde::from_slice(bytes).map_err(ser_error)
+    }
+}
+
+/// CBOR, a self-describing binary format
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    const KIND: CodecKind = CodecKind::Cbor;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        let mut bytes = vec![];
+        ciborium::into_writer(value, &mut bytes).map_err(ser_error)?;
+        Ok(bytes)
+    }
+
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError> {
+        ciborium::into_writer(value, writer).map_err(ser_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError> {
+        ciborium::from_reader(bytes).map_err(ser_error)
+    }
+}
+
+/// JSON, the slowest but most flexible codec
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    const KIND: CodecKind = CodecKind::Json;
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        serde_json::to_vec(value).map_err(ser_error)
+    }
+
+    fn encode_into<T: Serialize + ?Sized, W: Write>(
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError> {
+        serde_json::to_writer(writer, value).map_err(ser_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, ProcessFunError> {
+        serde_json::from_slice(bytes).map_err(ser_error)
+    }
+}
+
+#[cfg(any(
+    feature = "postcard",
+    feature = "msgpack",
+    feature = "cbor",
+    feature = "json"
+))]
+fn ser_error(e: impl std::fmt::Display) -> ProcessFunError {
+    ProcessFunError::SerError(e.to_string())
+}
+
+/// A codec selected at runtime, recorded by its id in the stream header.
+///
+/// Only the codecs whose feature is enabled exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+#[repr(u8)]
+pub enum CodecKind {
+    #[default]
+    Bincode = 0,
+    #[cfg(feature = "postcard")]
+    Postcard = 1,
+    #[cfg(feature = "msgpack")]
+    MessagePack = 2,
+    #[cfg(feature = "cbor")]
+    Cbor = 3,
+    #[cfg(feature = "json")]
+    Json = 4,
+}
+
+impl CodecKind {
+    /// The codecs enabled in this build
+    pub const ALL: &'static [CodecKind] = &[
+        Self::Bincode,
+        #[cfg(feature = "postcard")]
+        Self::Postcard,
+        #[cfg(feature = "msgpack")]
+        Self::MessagePack,
+        #[cfg(feature = "cbor")]
+        Self::Cbor,
+        #[cfg(feature = "json")]
+        Self::Json,
+    ];
+
+    /// The id recorded in the stream header
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    /// The codec with `id`, if it is enabled in this build
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.id() == id)
+    }
+
+    /// The name used in `#[process(codec = "...")]`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bincode => "bincode",
+            #[cfg(feature = "postcard")]
+            Self::Postcard => "postcard",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "msgpack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "cbor",
+            #[cfg(feature = "json")]
+            Self::Json => "json",
+        }
+    }
+
+    /// Serialize `value` with this codec
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>, ProcessFunError> {
+        match self {
+            Self::Bincode => Bincode::encode(value),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::encode(value),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::encode(value),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor::encode(value),
+            #[cfg(feature = "json")]
+            Self::Json => Json::encode(value),
+        }
+    }
+
+    /// Serialize `value` into `writer` with this codec
+    pub fn encode_into<T: Serialize + ?Sized, W: Write>(
+        self,
+        value: &T,
+        writer: W,
+    ) -> Result<(), ProcessFunError> {
+        match self {
+            Self::Bincode => Bincode::encode_into(value, writer),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::encode_into(value, writer),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::encode_into(value, writer),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor::encode_into(value, writer),
+            #[cfg(feature = "json")]
+            Self::Json => Json::encode_into(value, writer),
+        }
+    }
+
+    /// Deserialize a value encoded with this codec
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, ProcessFunError> {
+        match self {
+            Self::Bincode => Bincode::decode(bytes),
+            #[cfg(feature = "postcard")]
+            Self::Postcard => Postcard::decode(bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePack::decode(bytes),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => Cbor::decode(bytes),
+            #[cfg(feature = "json")]
+            Self::Json => Json::decode(bytes),
+        }
+    }
+}
+
+/// Use `codec` for all calls that do not select one. Calls already started keep their codec.
+pub fn set_default(codec: CodecKind) {
+    DEFAULT_CODEC.store(codec.id(), Ordering::SeqCst);
+}
+
+/// The codec used by calls that do not select one
+pub fn default_codec() -> CodecKind {
+    CodecKind::from_id(DEFAULT_CODEC.load(Ordering::SeqCst)).unwrap_or_default()
+}
@@ -0,0 +1,170 @@
+//! Async support: `ProcessWrapper<T>` implements `Future<Output = Result<T, ProcessFunError>>`.
+//!
+//! The future is driven by readiness of the result pipe and, once the child closed it, of the
+//! child's pidfd. No thread is blocked while waiting. With the `tokio` feature the tokio
+//! reactor is used, otherwise the `async-io` feature provides a runtime-agnostic reactor.
+
+use crate::{ProcessFunError, ProcessWrapper};
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+#[cfg(feature = "tokio")]
+type Registration = tokio::io::unix::AsyncFd<Fd>;
+
+#[cfg(not(feature = "tokio"))]
+type Registration = async_io::Async<Fd>;
+
+/// File descriptor registered with the reactor, owned by the `ProcessWrapper`
+#[derive(Debug)]
+struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsFd for Fd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+/// Reactor registrations of the pipe and pidfd, created on first poll
+#[derive(Debug, Default)]
+pub(crate) struct AsyncState {
+    pipe: Option<Registration>,
+    pidfd: Option<Registration>,
+}
+
+impl AsyncState {
+    /// Deregister the file descriptors. Must happen before they are closed.
+    pub(crate) fn clear(&mut self) {
+        self.pipe = None;
+        self.pidfd = None;
+    }
+}
+
+#[cfg(feature = "tokio")]
+fn register(fd: RawFd) -> io::Result<Registration> {
+    tokio::io::unix::AsyncFd::with_interest(Fd(fd), tokio::io::Interest::READABLE)
+}
+
+#[cfg(not(feature = "tokio"))]
+fn register(fd: RawFd) -> io::Result<Registration> {
+    // Reads are guarded by `poll`, so the descriptor may stay in blocking mode
+    async_io::Async::new_nonblocking(Fd(fd))
+}
+
+#[cfg(feature = "tokio")]
+fn poll_readable(registration: &Registration, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let mut guard = ready!(registration.poll_read_ready(cx))?;
+    guard.clear_ready();
+    Poll::Ready(Ok(()))
+}
+
+#[cfg(not(feature = "tokio"))]
+fn poll_readable(registration: &Registration, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    registration.poll_readable(cx)
+}
+
+fn poll_registered(
+    registration: &mut Option<Registration>,
+    fd: RawFd,
+    cx: &mut Context<'_>,
+) -> Poll<Result<(), ProcessFunError>> {
+    let registration = match registration {
+        Some(registration) => registration,
+        None => registration.insert(register(fd)?),
+    };
+    ready!(poll_readable(registration, cx))?;
+    Poll::Ready(Ok(()))
+}
+
+impl<T> ProcessWrapper<T> {
+    /// Wait until the pipe has data or was closed by the child
+    fn poll_pipe(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProcessFunError>> {
+        match &self.receiver {
+            Some(receiver) => {
+                let fd = receiver.as_fd().as_raw_fd();
+                poll_registered(&mut self.async_state.pipe, fd, cx)
+            }
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Wait until the child has exited, so reaping it does not block
+    fn poll_exit(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ProcessFunError>> {
+        match (&self.pidfd, self.status) {
+            (Some(pidfd), None) => {
+                let fd = pidfd.as_raw_fd();
+                poll_registered(&mut self.async_state.pidfd, fd, cx)
+            }
+            // Without pidfd support, the closed pipe is the best indication that the child
+            // is exiting, so reaping will only block briefly
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<T> Future for ProcessWrapper<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    type Output = Result<T, ProcessFunError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        this.ensure_not_finished()?;
+
+        // Read everything the child writes until it closes the pipe. Errors are kept until the
+        // child has exited, which may only be noticed by a later poll.
+        let outcome = loop {
+            let result = match this.read_available() {
+                Ok(()) if this.receiver.is_none() => break this.ensure_start_time(),
+                Ok(()) => ready!(this.poll_pipe(cx)),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                this.read_error.get_or_insert(e);
+                this.close_pipe();
+            }
+        };
+
+        // Errors are explained by the exit status where possible, so wait for it either way
+        ready!(this.poll_exit(cx))?;
+        let status = this.reap()?;
+        Poll::Ready(this.finish(outcome, status))
+    }
+}
+
+impl<T> ProcessWrapper<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    /// Asynchronously wait for the process to complete with a timeout, using the timer of the
+    /// async runtime. The process is killed if it exceeds the timeout.
+    pub async fn timeout_async(&mut self, duration: Duration) -> Result<T, ProcessFunError> {
+        #[cfg(feature = "tokio")]
+        let result = tokio::time::timeout(duration, &mut *self).await.ok();
+
+        #[cfg(not(feature = "tokio"))]
+        let result = futures_lite::future::or(async { Some((&mut *self).await) }, async {
+            async_io::Timer::after(duration).await;
+            None
+        })
+        .await;
+
+        match result {
+            Some(result) => result,
+            None => {
+                self.abort()?;
+                Err(ProcessFunError::TimeoutError)
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Landlock rulesets restricting the paths a process function may read and write.
+//!
+//! The ruleset is applied in the child after the start-time handshake, before the seccomp
+//! filter. Landlock restricts the calling thread and the threads and processes it creates
+//! afterwards, so it covers the function but not the deadline watchdog, which never touches
+//! the filesystem. Access rights unknown to the running kernel are left unrestricted, while a
+//! kernel without Landlock fails the call unless the restrictions are best effort.
+
+use crate::ProcessFunError;
+use nix::libc;
+use nix::libc::{c_int, c_long, c_void};
+use nix::sys::prctl;
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Flag of `landlock_create_ruleset` returning the supported ABI version
+const CREATE_RULESET_VERSION: u32 = 1;
+
+/// Type of rules allowing access beneath a path
+const RULE_PATH_BENEATH: c_int = 1;
+
+/// Filesystem access rights, with the ABI version that introduced them
+const EXECUTE: u64 = 1 << 0;
+const WRITE_FILE: u64 = 1 << 1;
+const READ_FILE: u64 = 1 << 2;
+const READ_DIR: u64 = 1 << 3;
+const REMOVE_DIR: u64 = 1 << 4;
+const REMOVE_FILE: u64 = 1 << 5;
+const MAKE_CHAR: u64 = 1 << 6;
+const MAKE_DIR: u64 = 1 << 7;
+const MAKE_REG: u64 = 1 << 8;
+const MAKE_SOCK: u64 = 1 << 9;
+const MAKE_FIFO: u64 = 1 << 10;
+const MAKE_BLOCK: u64 = 1 << 11;
+const MAKE_SYM: u64 = 1 << 12;
+/// Since ABI 2
+const REFER: u64 = 1 << 13;
+/// Since ABI 3
+const TRUNCATE: u64 = 1 << 14;
+/// Since ABI 5
+const IOCTL_DEV: u64 = 1 << 15;
+
+const READ_ACCESS: u64 = EXECUTE | READ_FILE | READ_DIR;
+const WRITE_ACCESS: u64 = WRITE_FILE
+    | REMOVE_DIR
+    | REMOVE_FILE
+    | MAKE_CHAR
+    | MAKE_DIR
+    | MAKE_REG
+    | MAKE_SOCK
+    | MAKE_FIFO
+    | MAKE_BLOCK
+    | MAKE_SYM
+    | REFER
+    | TRUNCATE
+    | IOCTL_DEV;
+
+/// The rights that can be granted on a file rather than a directory
+const FILE_ACCESS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE | IOCTL_DEV;
+
+/// `struct landlock_ruleset_attr`, limited to the field of ABI 1 so every version accepts it
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+/// The paths a process function may read and write.
+///
+/// Reading and writing are restricted independently: until `read` or `deny_read` is called,
+/// the function may read anywhere, and likewise for writing. Paths allowed for writing may
+/// also be read. Each path grants access to everything beneath it, and must exist when the
+/// function is called.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsAccess {
+    pub(crate) read: Option<Vec<PathBuf>>,
+    pub(crate) write: Option<Vec<PathBuf>>,
+    pub(crate) best_effort: bool,
+}
+
+impl FsAccess {
+    /// Create options that do not restrict filesystem access
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow reading files, listing directories and executing files beneath `path`, and deny
+    /// it beneath paths that are not allowed
+    pub fn read(mut self, path: impl Into<PathBuf>) -> Self {
+        self.read.get_or_insert_with(Vec::new).push(path.into());
+        self
+    }
+
+    /// Allow writing, creating, removing and renaming files and directories beneath `path`,
+    /// and deny it beneath paths that are not allowed
+    pub fn write(mut self, path: impl Into<PathBuf>) -> Self {
+        self.write.get_or_insert_with(Vec::new).push(path.into());
+        self
+    }
+
+    /// Deny reading beneath any path not allowed with `read` or `write`, even if there is none
+    pub fn deny_read(mut self) -> Self {
+        self.read.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Deny writing beneath any path not allowed with `write`, even if there is none
+    pub fn deny_write(mut self) -> Self {
+        self.write.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Run the function without these restrictions if the kernel does not support Landlock,
+    /// instead of failing with `ProcessFunError::LandlockUnsupported`
+    pub fn best_effort(mut self) -> Self {
+        self.best_effort = true;
+        self
+    }
+
+    fn is_restricted(&self) -> bool {
+        self.read.is_some() || self.write.is_some()
+    }
+}
+
+/// Restrict the filesystem access of the calling thread and its future children to `access`
+pub(crate) fn restrict(access: &FsAccess) -> Result<(), ProcessFunError> {
+    if !access.is_restricted() {
+        return Ok(());
+    }
+
+    let Some(abi) = abi_version() else {
+        return if access.best_effort {
+            Ok(())
+        } else {
+            Err(ProcessFunError::LandlockUnsupported)
+        };
+    };
+    let supported = match abi {
+        1 => REFER - 1,
+        2 => TRUNCATE - 1,
+        3 | 4 => IOCTL_DEV - 1,
+        _ => (IOCTL_DEV << 1) - 1,
+    };
+    let mut handled = 0;
+    if access.read.is_some() {
+        handled |= READ_ACCESS;
+    }
+    if access.write.is_some() {
+        handled |= WRITE_ACCESS;
+    }
+    handled &= supported;
+
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    // SAFETY: `attr` is a valid ruleset attribute of the given size
+    let ruleset =
+        check(unsafe { landlock_create_ruleset(&attr, std::mem::size_of::<RulesetAttr>(), 0) })
+            .map_err(|e| landlock_error("create Landlock ruleset", e))?;
+    // SAFETY: the call returned a new file descriptor
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as c_int) };
+
+    let read_paths = access.read.iter().flatten();
+    let write_paths = access.write.iter().flatten();
+    for (path, allowed) in read_paths
+        .map(|path| (path, READ_ACCESS))
+        .chain(write_paths.map(|path| (path, READ_ACCESS | WRITE_ACCESS)))
+    {
+        add_rule(&ruleset, path, allowed & handled)?;
+    }
+
+    // Needed to restrict itself without privileges
+    prctl::set_no_new_privs()
+        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to set no_new_privs: {}", e)))?;
+    // SAFETY: `ruleset` is a Landlock ruleset
+    check(unsafe { landlock_restrict_self(ruleset.as_raw_fd(), 0) })
+        .map_err(|e| landlock_error("apply Landlock ruleset", e))?;
+    Ok(())
+}
+
+/// Allow `allowed` beneath `path` in `ruleset`
+fn add_rule(ruleset: &OwnedFd, path: &Path, allowed: u64) -> Result<(), ProcessFunError> {
+    let parent = File::options()
+        .read(true)
+        .custom_flags(libc::O_PATH)
+        .open(path)
+        .map_err(|e| landlock_error(&format!("open {}", path.display()), e))?;
+    let is_dir = parent
+        .metadata()
+        .map_err(|e| landlock_error(&format!("inspect {}", path.display()), e))?
+        .is_dir();
+
+    let attr = PathBeneathAttr {
+        allowed_access: if is_dir {
+            allowed
+        } else {
+            allowed & FILE_ACCESS
+        },
+        parent_fd: parent.as_raw_fd(),
+    };
+    // SAFETY: `attr` is a valid rule of the given type
+    check(unsafe {
+        landlock_add_rule(
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr as *const c_void,
+            0,
+        )
+    })
+    .map_err(|e| landlock_error(&format!("allow access to {}", path.display()), e))?;
+    Ok(())
+}
+
+/// The Landlock ABI version of the running kernel, or `None` if it does not support Landlock
+fn abi_version() -> Option<c_long> {
+    // SAFETY: querying the version takes no attribute
+    check(unsafe { landlock_create_ruleset(std::ptr::null(), 0, CREATE_RULESET_VERSION) }).ok()
+}
+
+unsafe fn landlock_create_ruleset(attr: *const RulesetAttr, size: usize, flags: u32) -> c_long {
+    libc::syscall(libc::SYS_landlock_create_ruleset, attr, size, flags)
+}
+
+unsafe fn landlock_add_rule(
+    ruleset: c_int,
+    rule_type: c_int,
+    attr: *const c_void,
+    flags: u32,
+) -> c_long {
+    libc::syscall(libc::SYS_landlock_add_rule, ruleset, rule_type, attr, flags)
+}
+
+unsafe fn landlock_restrict_self(ruleset: c_int, flags: u32) -> c_long {
+    libc::syscall(libc::SYS_landlock_restrict_self, ruleset, flags)
+}
+
+fn check(result: c_long) -> io::Result<c_long> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn landlock_error(action: &str, e: io::Error) -> ProcessFunError {
+    ProcessFunError::ProcessError(format!("Failed to {}: {}", action, e))
+}
@@ -9,17 +9,60 @@
 use interprocess::unnamed_pipe::{Recver, Sender};
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
 use nix::unistd::{fork, pipe2, ForkResult, Pid};
 use serde::{Deserialize, Serialize};
-use std::io::prelude::*;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::os::fd::{AsFd, OwnedFd};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::PathBuf;
-use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
-use std::{fmt, mem};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, fs};
 use thiserror::Error;
 
+#[cfg(feature = "cgroup")]
+mod cgroup;
+mod channel;
+mod child;
+pub mod codec;
+#[cfg(any(feature = "tokio", feature = "async-io"))]
+mod future;
+mod landlock;
+mod namespaces;
+mod options;
+mod output;
+mod pidfd;
+mod privileges;
+pub mod progress;
+pub mod reaper;
+mod report;
+mod seccomp;
+mod shared_memory;
+mod stream;
+pub mod wire;
+
+#[cfg(feature = "cgroup")]
+pub use cgroup::{CgroupLimits, CgroupStats, CpuStat, MemoryEvents};
+pub use channel::ProcessChannel;
+pub use child::{
+    finish_child, in_process_function, restrict_child, send_items, setup_child, start_child,
+};
+pub use codec::{Codec, CodecKind};
+pub use landlock::FsAccess;
+pub use namespaces::Namespaces;
+pub use options::*;
+pub use output::{CapturedOutput, OutputMode, OutputPipes, ProcessOutput, DEFAULT_CAPTURE_LIMIT};
+pub use privileges::Privileges;
+pub use report::ProcessReport;
+pub use seccomp::SeccompFilter;
+pub use shared_memory::{SharedMemory, DEFAULT_SHARED_MEMORY_THRESHOLD};
+pub use stream::ProcessStream;
+
 // Re-export specific items needed by generated code with clear namespacing
 pub mod sys {
     pub use nix::sys::signal::{self, Signal};
@@ -27,7 +70,7 @@ pub mod sys {
     pub use nix::unistd::{fork, getpid, ForkResult, Pid};
 }
 
-// Use a more efficient binary serialization format
+// bincode helpers, the default codec. The codec of a call is selected with `ProcessOptions::codec`.
 pub mod ser {
     use bincode::{deserialize, serialize, Error};
     use serde::{Deserialize, Serialize};
@@ -45,10 +88,35 @@ pub mod ser {
 #[derive(Debug)]
 pub struct ProcessWrapper<T> {
     child_pid: Pid,
-    start_time: Option<SystemTime>,
+    start_time: Option<u64>,
     receiver: Option<Recver>,
-    result: Arc<Mutex<Option<Vec<u8>>>>,
-    _ghost: std::marker::PhantomData<T>,
+    decoder: wire::FrameDecoder,
+    /// The codec of the values the child sends
+    codec: CodecKind,
+    final_frame: Option<wire::Frame>,
+    items: VecDeque<Vec<u8>>,
+    progress: VecDeque<Vec<u8>>,
+    read_error: Option<ProcessFunError>,
+    finished: bool,
+    pidfd: Option<OwnedFd>,
+    status: Option<WaitStatus>,
+    /// When the child was forked, for the wall time of its report
+    started: Instant,
+    report: Option<ProcessReport>,
+    capture: output::Capture,
+    output: Option<(CapturedOutput, CapturedOutput)>,
+    shared_memory: Option<SharedMemory>,
+    exit_slot: Option<Arc<reaper::ExitSlot>>,
+    termination_policy: TerminationPolicy,
+    termination: Option<Termination>,
+    group_leader: bool,
+    #[cfg(feature = "cgroup")]
+    cgroup: Option<cgroup::Cgroup>,
+    #[cfg(feature = "cgroup")]
+    cgroup_stats: Option<CgroupStats>,
+    #[cfg(any(feature = "tokio", feature = "async-io"))]
+    async_state: future::AsyncState,
+    _ghost: std::marker::PhantomData<fn() -> T>,
 }
 
 impl<T> fmt::Display for ProcessWrapper<T> {
@@ -63,94 +131,239 @@ where
 {
     /// Create a new ProcessWrapper
     pub fn new(child_pid: Pid, receiver: Recver) -> Self {
+        Self::with_options(child_pid, receiver, &ProcessOptions::default())
+    }
+
+    /// Create a new ProcessWrapper for a child started with `options`
+    pub fn with_options(child_pid: Pid, receiver: Recver, options: &ProcessOptions) -> Self {
         Self {
             child_pid,
             start_time: None,
             receiver: Some(receiver),
-            result: Arc::new(Mutex::new(None)),
+            decoder: wire::FrameDecoder::new().expect_codec(options.codec_kind()),
+            codec: options.codec_kind(),
+            final_frame: None,
+            items: VecDeque::new(),
+            progress: VecDeque::new(),
+            read_error: None,
+            finished: false,
+            pidfd: pidfd::open(child_pid),
+            status: None,
+            started: Instant::now(),
+            report: None,
+            capture: Default::default(),
+            output: None,
+            shared_memory: None,
+            exit_slot: reaper::register(child_pid),
+            termination_policy: options.termination,
+            termination: None,
+            group_leader: options.leads_process_group(),
+            #[cfg(feature = "cgroup")]
+            cgroup: options
+                .cgroup
+                .as_ref()
+                .and_then(|limits| cgroup::Cgroup::of_child(limits, child_pid)),
+            #[cfg(feature = "cgroup")]
+            cgroup_stats: None,
+            #[cfg(any(feature = "tokio", feature = "async-io"))]
+            async_state: Default::default(),
             _ghost: std::marker::PhantomData,
         }
     }
 
     /// Wait for the process to complete and return its result
     pub fn wait(&mut self) -> Result<T, ProcessFunError> {
-        // Ensure we have the start time for process validation
-        self.ensure_start_time()?;
+        self.ensure_not_finished()?;
 
-        // Check if we already have a result
-        if let Some(bytes) = self.result.lock().unwrap().take() {
-            return ser::from_slice(&bytes).map_err(ProcessFunError::from);
-        }
+        // Read result from pipe, the start time is read first for process validation
+        let outcome = self.ensure_start_time().and_then(|_| self.read_to_end());
 
-        // Read result from pipe
-        let receiver = self.receiver.take().ok_or_else(|| {
-            ProcessFunError::ProcessError("Process already completed".to_string())
-        })?;
+        // The pipe is closed, so the child is exiting and can be reaped
+        let status = self.reap()?;
+        self.finish(outcome, status)
+    }
 
-        let mut receiver = receiver;
-        let result_bytes = read_from_pipe(&mut receiver)?;
-        let result: T = ser::from_slice(&result_bytes)?;
+    /// Wait for the process to complete and return its result along with how much it used.
+    ///
+    /// The report is also available when the function failed, and only missing if the child
+    /// could not be reaped.
+    pub fn wait_with_report(&mut self) -> (Result<T, ProcessFunError>, Option<ProcessReport>) {
+        let result = self.wait();
+        (result, self.report)
+    }
 
-        Ok(result)
+    /// Wait for the process to complete and return its result along with the stdout and
+    /// stderr captured with `OutputMode::Capture`.
+    ///
+    /// The output is also available when the function failed.
+    pub fn wait_with_output(&mut self) -> ProcessOutput<T> {
+        let result = self.wait();
+        let (stdout, stderr) = self.take_output().unwrap_or_default();
+        ProcessOutput {
+            result,
+            stdout,
+            stderr,
+        }
     }
 
-    /// Wait for the process to complete with a timeout
-    pub fn timeout(&mut self, duration: Duration) -> Result<T, ProcessFunError> {
-        // Ensure we have the start time for process validation
-        self.ensure_start_time()?;
-
-        // Take ownership of the receiver
-        let receiver = self.receiver.take().ok_or_else(|| {
-            ProcessFunError::ProcessError("Process already completed".to_string())
-        })?;
-
-        // Create a channel for the calculation thread to signal completion
-        let (tx, rx) = mpsc::channel();
-
-        // Spawn thread to read from pipe
-        let result = self.result.clone();
-        std::thread::spawn(move || {
-            let mut receiver = receiver;
-            if let Ok(bytes) = read_from_pipe(&mut receiver) {
-                *result.lock().unwrap() = Some(bytes);
-                let _ = tx.send(true); // Signal completion
-            }
-        });
+    /// Check whether the process has completed without blocking.
+    ///
+    /// Returns `Ok(None)` while the child is still running, reading whatever it has written
+    /// so far, similar to `std::process::Child::try_wait`.
+    pub fn try_wait(&mut self) -> Result<Option<T>, ProcessFunError> {
+        self.ensure_not_finished()?;
+
+        // The pipe is closed on errors, which are reported once the child has exited, as a
+        // child that sent a corrupt frame may keep running
+        if let Err(e) = self.read_available() {
+            self.read_error.get_or_insert(e);
+        }
+        if self.receiver.is_some() {
+            return Ok(None);
+        }
 
-        // Wait for either timeout or completion
-        match rx.recv_timeout(duration) {
-            Ok(_) => {
-                // Process completed within timeout
-                if let Some(bytes) = self.result.lock().unwrap().take() {
-                    return ser::from_slice(&bytes).map_err(ProcessFunError::from);
-                }
-                // This shouldn't happen as we got a completion signal
-                Err(ProcessFunError::ProcessError(
-                    "Process result not found".to_string(),
-                ))
+        // The pipe is closed, the result is available once the child has exited
+        match self.try_reap()? {
+            Some(status) => {
+                let outcome = self.ensure_start_time();
+                self.finish(outcome, status).map(Some)
             }
-            Err(_) => {
+            None => Ok(None),
+        }
+    }
+
+    /// Wait for the process to complete with a timeout.
+    ///
+    /// The process is killed if it does not complete in time. Use `wait_timeout` to keep it
+    /// running instead.
+    pub fn timeout(&mut self, duration: Duration) -> Result<T, ProcessFunError> {
+        match self.wait_timeout(duration)? {
+            Some(result) => Ok(result),
+            None => {
                 // Timeout occurred
                 self.abort()?;
                 Err(ProcessFunError::TimeoutError)
             }
         }
     }
+
+    /// Wait for the process to complete for at most `duration`.
+    ///
+    /// Returns `Ok(None)` if the process is still running afterwards. The process is left
+    /// running, so it can be waited on again or aborted.
+    pub fn wait_timeout(&mut self, duration: Duration) -> Result<Option<T>, ProcessFunError> {
+        match Instant::now().checked_add(duration) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => self.wait().map(Some),
+        }
+    }
+
+    /// Wait for the process to complete until `deadline`.
+    ///
+    /// Returns `Ok(None)` if the process is still running at the deadline. The process is
+    /// left running, so it can be waited on again or aborted.
+    pub fn wait_deadline(&mut self, deadline: Instant) -> Result<Option<T>, ProcessFunError> {
+        loop {
+            if let Some(result) = self.try_wait()? {
+                return Ok(Some(result));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.poll_progress(remaining)?;
+        }
+    }
 }
 
-#[inline]
-pub fn stat_pid_start(pid: Pid) -> Result<SystemTime, ProcessFunError> {
-    let proc_path = format!("/proc/{}/stat", pid.as_raw());
-    nix::sys::stat::stat(proc_path.as_str())
-        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to stat process: {}", e)))
-        .and_then(|stat| {
-            SystemTime::UNIX_EPOCH
-                .checked_add(Duration::from_secs(stat.st_ctime as u64))
-                .ok_or_else(|| {
-                    ProcessFunError::ProcessError(
-                        "Failed to calculate process start time".to_string(),
-                    )
-                })
+impl<T> ProcessWrapper<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    /// Produce the final result once the pipe is closed and the child has been reaped
+    fn finish(
+        &mut self,
+        outcome: Result<(), ProcessFunError>,
+        status: WaitStatus,
+    ) -> Result<T, ProcessFunError> {
+        self.finished = true;
+        self.close_pipe();
+        // An error that occurred while reading in the background explains everything after it
+        let outcome = match self.read_error.take() {
+            Some(e) => Err(e),
+            None => outcome.and_then(|_| self.decoder.finish()),
+        };
+        #[cfg(feature = "cgroup")]
+        let received_outcome = self.final_frame.is_some();
+        let result = complete(
+            self.final_frame.take(),
+            outcome,
+            status,
+            self.shared_memory.take().as_ref(),
+        );
+        #[cfg(feature = "cgroup")]
+        let result = self.finish_cgroup(result, received_outcome);
+        result
+    }
+
+    /// Read the accounting of the child's cgroup and remove it. An error is explained by the
+    /// OOM killer if it killed a process before the child could report an outcome.
+    #[cfg(feature = "cgroup")]
+    fn finish_cgroup(
+        &mut self,
+        result: Result<T, ProcessFunError>,
+        received_outcome: bool,
+    ) -> Result<T, ProcessFunError> {
+        let Some(cgroup) = self.cgroup.take() else {
+            return result;
+        };
+        let stats = cgroup.stats();
+        self.cgroup_stats = Some(stats);
+        match result {
+            Err(_) if !received_outcome && stats.oom_killed() => Err(ProcessFunError::OutOfMemory),
+            result => result,
+        }
+    }
+}
+
+/// Combine what was read from the pipe with the exit status of the child.
+///
+/// The final frame takes precedence. The exit status is only used to explain why no final
+/// frame was received, e.g. because the child crashed or was killed.
+fn complete<T: serde::de::DeserializeOwned>(
+    final_frame: Option<wire::Frame>,
+    outcome: Result<(), ProcessFunError>,
+    status: WaitStatus,
+    shared_memory: Option<&SharedMemory>,
+) -> Result<T, ProcessFunError> {
+    let abnormal = !matches!(status, WaitStatus::Exited(_, 0));
+    match (final_frame, outcome) {
+        (Some(frame), _) if frame.kind == wire::FrameKind::SharedResult => match shared_memory {
+            Some(memory) => memory.decode(&frame),
+            None => frame.into_outcome(),
+        },
+        (Some(frame), _) => frame.into_outcome(),
+        (None, Ok(())) => Err(ProcessFunError::from(status)),
+        (None, Err(_)) if abnormal => Err(ProcessFunError::from(status)),
+        (None, Err(e)) => Err(e),
+    }
+}
+
+/// The start time of a process in clock ticks since boot, from the `starttime` field of
+/// `/proc/<pid>/stat`. Together with the PID it identifies a process, as a reused PID belongs
+/// to a process that started later.
+pub fn pid_start_time(pid: Pid) -> Result<u64, ProcessFunError> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid.as_raw())).map_err(|e| {
+        ProcessFunError::ProcessError(format!("Failed to read process stat: {}", e))
+    })?;
+
+    // The command name may contain spaces and parentheses, the fields after it do not
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(STARTTIME_FIELD))
+        .and_then(|field| field.parse().ok())
+        .ok_or_else(|| {
+            ProcessFunError::ProcessError("Failed to parse process start time".to_string())
         })
 }
 
@@ -158,73 +371,432 @@ impl<T> ProcessWrapper<T> {
     /// Lazily read the start time from pipe if not already read
     #[inline]
     fn ensure_start_time(&mut self) -> Result<(), ProcessFunError> {
-        if self.start_time.is_some() {
-            return Ok(());
+        while self.start_time.is_none() && self.receiver.is_some() {
+            self.read_chunk()?;
         }
+        if self.start_time.is_none() {
+            return Err(ProcessFunError::ProcessError(
+                "Child process exited before sending its start time".to_string(),
+            ));
+        }
+        Ok(())
+    }
 
-        if let Some(receiver) = &mut self.receiver {
-            let start_time = read_start_time_from_pipe(receiver)?;
-            self.start_time = Some(start_time);
-            Ok(())
-        } else {
+    #[inline]
+    fn ensure_not_finished(&self) -> Result<(), ProcessFunError> {
+        if self.finished {
             Err(ProcessFunError::ProcessError(
                 "Process already completed".to_string(),
             ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Block until the child closed the pipe
+    fn read_to_end(&mut self) -> Result<(), ProcessFunError> {
+        while self.receiver.is_some() {
+            self.read_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Read whatever the child has written so far without blocking
+    fn read_available(&mut self) -> Result<(), ProcessFunError> {
+        while let Some(receiver) = &self.receiver {
+            let mut fds = [PollFd::new(receiver.as_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, PollTimeout::ZERO) {
+                Ok(0) => break,
+                Ok(_) => self.read_chunk()?,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    return Err(ProcessFunError::ProcessError(format!(
+                        "Failed to poll pipe: {}",
+                        e
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Perform a single read from the pipe and handle the frames it completed. The pipe is
+    /// closed once the child closed its end, or when nothing more will be read from it.
+    fn read_chunk(&mut self) -> Result<(), ProcessFunError> {
+        let Some(receiver) = &mut self.receiver else {
+            return Ok(());
+        };
+
+        let result = match self.decoder.read_from(receiver, READ_CHUNK_SIZE) {
+            Ok(0) => {
+                self.close_pipe();
+                Ok(())
+            }
+            Ok(_) => self.handle_frames(),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(ProcessFunError::ProcessError(format!(
+                "Failed to read from pipe: {}",
+                e
+            ))),
+        };
+
+        // A child blocked on writing to the pipe must not keep us from reaping it
+        if result.is_err() {
+            self.close_pipe();
+        }
+        result
+    }
+
+    fn handle_frames(&mut self) -> Result<(), ProcessFunError> {
+        while let Some(frame) = self.decoder.next_frame()? {
+            match frame.kind {
+                wire::FrameKind::Start => self.start_time = Some(frame.start_time()?),
+                wire::FrameKind::Log => {
+                    eprintln!("{}", String::from_utf8_lossy(&frame.payload));
+                }
+                wire::FrameKind::Progress => self.progress.push_back(frame.payload),
+                wire::FrameKind::Item => self.items.push_back(frame.payload),
+                _ => self.final_frame = Some(frame),
+            }
+        }
+        Ok(())
+    }
+
+    /// Block for at most `timeout` until there is something to read from the pipe or, once
+    /// the pipe is closed, until the child exits
+    fn poll_progress(&mut self, timeout: Duration) -> Result<(), ProcessFunError> {
+        let fd = match (&self.receiver, &self.pidfd) {
+            (Some(receiver), _) => receiver.as_fd(),
+            (None, Some(pidfd)) if self.status.is_none() => pidfd.as_fd(),
+            _ => {
+                // Without a pidfd there is nothing to poll for the exit of the child
+                std::thread::sleep(timeout.min(EXIT_POLL_INTERVAL));
+                return Ok(());
+            }
+        };
+
+        match poll(
+            &mut [PollFd::new(fd, PollFlags::POLLIN)],
+            poll_timeout(timeout),
+        ) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(e) => Err(ProcessFunError::ProcessError(format!(
+                "Failed to poll child process: {}",
+                e
+            ))),
         }
     }
 
-    /// Check if the process is still the same one we created
+    #[inline]
+    fn close_pipe(&mut self) {
+        // Stop watching the pipe before its file descriptor is closed
+        #[cfg(any(feature = "tokio", feature = "async-io"))]
+        self.async_state.clear();
+
+        let _ = self.receiver.take();
+    }
+
+    /// Check if the process is still the same one we created, i.e. it has not been reaped
+    /// behind our back and its PID reused
     #[inline]
     fn is_same_process(&mut self) -> bool {
+        if let Some(pidfd) = &self.pidfd {
+            return pidfd::send_signal(pidfd.as_fd(), None).is_ok();
+        }
+
+        // Without a pidfd, compare the start time the child reported with the current one
         if self.ensure_start_time().is_err() {
             return false;
         }
-        // Ensure we have the start time for validation
-        if let Some(start_time) = self.start_time {
-            stat_pid_start(self.child_pid)
-                .map(|stat| stat == start_time)
-                .unwrap_or(false)
-        } else {
-            false
+        match self.start_time {
+            Some(start_time) => pid_start_time(self.child_pid)
+                .map(|current| current == start_time)
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    /// Progress updates the child sent with `progress::report` since they were last taken.
+    ///
+    /// Does not block, but takes in whatever the child has written so far. `P` must be the type
+    /// of the updates the child sent.
+    pub fn progress<P: serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> impl Iterator<Item = Result<P, ProcessFunError>> + '_ {
+        if let Err(e) = self.read_available() {
+            self.read_error.get_or_insert(e);
+        }
+        let codec = self.codec;
+        self.progress
+            .drain(..)
+            .map(move |payload| codec.decode(&payload))
+    }
+
+    /// The process id of the child
+    pub fn pid(&self) -> Pid {
+        self.child_pid
+    }
+
+    /// The exit status of the child, available once it has been reaped by `wait`,
+    /// `timeout` or `abort`
+    pub fn status(&self) -> Option<WaitStatus> {
+        self.status
+    }
+
+    /// How much the child used, available once it has been reaped by `wait`, `timeout` or
+    /// `abort`
+    pub fn report(&self) -> Option<&ProcessReport> {
+        self.report.as_ref()
+    }
+
+    /// Capture the streams of the child that `output` pipes to the parent
+    pub fn with_output(mut self, output: OutputPipes) -> Result<Self, ProcessFunError> {
+        self.capture = output.capture()?;
+        Ok(self)
+    }
+
+    /// Receive large results through `shared_memory`, see `ProcessOptions::shared_memory`
+    pub fn with_shared_memory(mut self, shared_memory: SharedMemory) -> Self {
+        self.shared_memory = Some(shared_memory);
+        self
+    }
+
+    /// The stdout and stderr captured from the child, available once it has been reaped by
+    /// `wait`, `timeout` or `abort`
+    pub fn take_output(&mut self) -> Option<(CapturedOutput, CapturedOutput)> {
+        self.output.take()
+    }
+
+    /// The directory of the cgroup the child was placed in, until the child has finished and
+    /// the cgroup was removed
+    #[cfg(feature = "cgroup")]
+    pub fn cgroup_path(&self) -> Option<&std::path::Path> {
+        self.cgroup.as_ref().map(cgroup::Cgroup::path)
+    }
+
+    /// The accounting of the child's cgroup, available once the child has finished
+    #[cfg(feature = "cgroup")]
+    pub fn cgroup_stats(&self) -> Option<&CgroupStats> {
+        self.cgroup_stats.as_ref()
+    }
+
+    /// Block until the child exits and collect its exit status
+    fn reap(&mut self) -> Result<WaitStatus, ProcessFunError> {
+        if let Some(status) = self.status {
+            return Ok(status);
         }
+
+        #[cfg(feature = "debug")]
+        eprintln!(
+            "[process-fun-debug] Reaping child process {}",
+            self.child_pid
+        );
+
+        loop {
+            // With the background reaper installed, it is the one collecting the status
+            let result = match (&self.exit_slot, &self.pidfd) {
+                (Some(slot), _) => slot.wait(),
+                (None, Some(pidfd)) => pidfd::wait(pidfd.as_fd(), self.child_pid, false),
+                (None, None) => report::wait4(self.child_pid, WaitPidFlag::empty()),
+            };
+            if let Some(status) = self.record_status(result)? {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Collect the exit status of the child if it has already exited
+    fn try_reap(&mut self) -> Result<Option<WaitStatus>, ProcessFunError> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+
+        let result = match (&self.exit_slot, &self.pidfd) {
+            (Some(slot), _) => match slot.try_get() {
+                Some(result) => result,
+                None => return Ok(None),
+            },
+            (None, Some(pidfd)) => pidfd::wait(pidfd.as_fd(), self.child_pid, true),
+            (None, None) => report::wait4(self.child_pid, WaitPidFlag::WNOHANG),
+        };
+        self.record_status(result)
     }
 
     #[inline]
-    fn kill(&mut self) -> Result<(), Errno> {
-        // Only kill if it's the same process we created
-        if self.is_same_process() {
-            match signal::kill(self.child_pid, Signal::SIGKILL) {
-                Ok(()) => Ok(()),
-                Err(Errno::ESRCH) => Ok(()), // Process already exited
-                Err(e) => Err(e),
+    fn record_status(
+        &mut self,
+        result: Result<report::Exit, Errno>,
+    ) -> Result<Option<WaitStatus>, ProcessFunError> {
+        match result {
+            Ok(report::Exit {
+                status: WaitStatus::StillAlive,
+                ..
+            })
+            | Err(Errno::EINTR) => Ok(None),
+            Ok(exit) => {
+                #[cfg(feature = "debug")]
+                eprintln!(
+                    "[process-fun-debug] Child process exited with {:?}",
+                    exit.status
+                );
+
+                self.status = Some(exit.status);
+                self.report = Some(ProcessReport::new(&exit, self.started));
+                // The child no longer writes, only what is left in the pipes is collected
+                self.output = Some(self.capture.finish());
+                Ok(Some(exit.status))
             }
+            Err(e) => Err(ProcessFunError::ProcessError(format!(
+                "Failed to wait for child process: {}",
+                e
+            ))),
+        }
+    }
+
+    #[inline]
+    fn signal(&mut self, signal: Signal) -> Result<(), ProcessFunError> {
+        // A reaped child's PID and process group id may already belong to other processes
+        if self.status.is_some() {
+            return Ok(());
+        }
+        let result = match self.exit_slot.clone() {
+            Some(slot) => slot
+                .unless_reaped(|| self.signal_unreaped(signal))
+                .unwrap_or(Ok(())),
+            None => self.signal_unreaped(signal),
+        };
+        result.map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to send {} to process: {}", signal, e))
+        })
+    }
+
+    #[inline]
+    fn signal_unreaped(&mut self, signal: Signal) -> Result<(), Errno> {
+        // The start time is sent once the child is set up, including its process group
+        let _ = self.ensure_start_time();
+
+        // Only signal if it's the same process we created
+        if !self.is_same_process() {
+            return Ok(()); // Different process with same PID, consider it "already killed"
+        }
+        let result = if self.group_leader {
+            // The unreaped child keeps its process group id from being reused
+            signal::killpg(self.child_pid, signal)
+        } else if let Some(pidfd) = &self.pidfd {
+            pidfd::send_signal(pidfd.as_fd(), Some(signal))
         } else {
-            Ok(()) // Different process with same PID, consider it "already killed"
+            signal::kill(self.child_pid, signal)
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(Errno::ESRCH) => Ok(()), // Process already exited
+            Err(e) => Err(e),
         }
     }
 
-    /// Abort the process
-    pub fn abort(&mut self) -> Result<(), ProcessFunError> {
-        // Take ownership of the receiver to ensure it's dropped
-        let _ = self.receiver.take();
+    /// Block until the child exits or `deadline` passes, without reading its result
+    fn wait_exit_deadline(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<Option<WaitStatus>, ProcessFunError> {
+        loop {
+            if let Some(status) = self.try_reap()? {
+                return Ok(Some(status));
+            }
 
-        self.kill().map_err(|e| {
-            ProcessFunError::ProcessError(format!("Failed to send SIGKILL to process: {}", e))
-        })?;
-        Ok(())
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.poll_progress(remaining)?;
+        }
+    }
+
+    /// Terminate the child according to the termination policy and reap it
+    fn terminate(&mut self) -> Result<Termination, ProcessFunError> {
+        let termination = if self.try_reap()?.is_some() {
+            Termination::AlreadyExited
+        } else {
+            let policy = self.termination_policy;
+            self.signal(policy.signal)?;
+
+            // Close the pipe so a child blocked on writing its result can exit
+            self.close_pipe();
+
+            let deadline = Instant::now() + policy.grace_period;
+            if policy.signal != Signal::SIGKILL && self.wait_exit_deadline(deadline)?.is_some() {
+                Termination::Graceful
+            } else {
+                self.signal(Signal::SIGKILL)?;
+                Termination::Killed
+            }
+        };
+
+        #[cfg(feature = "debug")]
+        eprintln!(
+            "[process-fun-debug] Terminated child process {}: {:?}",
+            self.child_pid, termination
+        );
+
+        self.reap()?;
+        self.termination = Some(termination);
+        Ok(termination)
+    }
+
+    /// Set how the child is terminated by `abort`, `timeout` and drop
+    pub fn set_termination_policy(&mut self, policy: TerminationPolicy) {
+        self.termination_policy = policy;
+    }
+
+    /// Builder-style variant of `set_termination_policy`
+    pub fn with_termination_policy(mut self, policy: TerminationPolicy) -> Self {
+        self.set_termination_policy(policy);
+        self
+    }
+
+    /// How the child ended if it was terminated by `abort` or `timeout`
+    pub fn termination(&self) -> Option<Termination> {
+        self.termination
+    }
+
+    /// Abort the process according to its termination policy and report how it ended
+    pub fn abort(&mut self) -> Result<Termination, ProcessFunError> {
+        // No result will be read anymore
+        self.finished = true;
+        let termination = self.terminate()?;
+        self.close_pipe();
+        Ok(termination)
     }
 }
 
 impl<T> Drop for ProcessWrapper<T> {
     fn drop(&mut self) {
-        // Take ownership of the receiver to ensure it's dropped
-        let _ = self.receiver.take();
+        // Attempt to terminate the process if it's still running. This happens before dropping
+        // the receiver, which is needed to validate the process identity.
+        let _ = self.terminate();
+
+        // Close the pipe to ensure it's dropped
+        self.close_pipe();
+
+        // Reap the child so it does not linger as a zombie
+        let _ = self.reap();
 
-        // Attempt to kill the process if it's still running
-        let _ = self.kill();
+        // Stop watching the pidfd before it is closed
+        #[cfg(any(feature = "tokio", feature = "async-io"))]
+        {
+            self.async_state = Default::default();
+        }
     }
 }
 
+/// Convert `timeout` for `poll`, rounding up so that sub-millisecond timeouts do not turn into
+/// busy loops
+#[inline]
+pub(crate) fn poll_timeout(timeout: Duration) -> PollTimeout {
+    let millis = timeout.as_nanos().div_ceil(1_000_000);
+    PollTimeout::try_from(millis).unwrap_or(PollTimeout::MAX)
+}
+
 /// Create a pipe for communication between parent and child processes
 #[inline]
 pub fn create_pipes() -> Result<(Recver, Sender), ProcessFunError> {
@@ -245,81 +817,63 @@ pub fn create_pipes() -> Result<(Recver, Sender), ProcessFunError> {
     Ok((recver, sender))
 }
 
-const SYSTEM_TIME_SIZE: usize = mem::size_of::<SystemTime>();
+/// Index of `starttime` among the fields of `/proc/<pid>/stat` following the command name
+const STARTTIME_FIELD: usize = 19;
 
-#[inline]
-fn system_time_to_bytes_unsafe(time: SystemTime) -> [u8; SYSTEM_TIME_SIZE] {
-    unsafe { mem::transmute::<SystemTime, [u8; SYSTEM_TIME_SIZE]>(time) }
-}
+/// Interval for checking whether the child exited when pidfds are not supported
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-#[inline]
-fn bytes_to_system_time_unsafe(bytes: [u8; SYSTEM_TIME_SIZE]) -> SystemTime {
-    unsafe { mem::transmute::<[u8; SYSTEM_TIME_SIZE], SystemTime>(bytes) }
-}
-
-/// Write time to pipe
-#[inline]
-pub fn write_time(fd: &mut Sender, time: SystemTime) -> Result<(), ProcessFunError> {
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Writing start time to pipe");
-
-    let time_bytes = system_time_to_bytes_unsafe(time);
-    fd.write_all(&time_bytes)?;
+/// Maximal number of bytes read from the pipe at once (the default pipe capacity)
+const READ_CHUNK_SIZE: usize = 64 * 1024;
 
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Successfully wrote start time to pipe");
-
-    Ok(())
-}
-
-/// Write data to a pipe and close it
-#[inline]
-pub fn write_to_pipe(mut fd: Sender, data: &[u8]) -> Result<(), ProcessFunError> {
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Writing {} bytes to pipe", data.len());
-
-    fd.write_all(data)
-        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to write to pipe: {}", e)))?;
-
-    // Let the pipe be automatically flushed and closed when dropped
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Successfully wrote data to pipe");
-
-    Ok(())
+thread_local! {
+    static PANIC_DETAILS: RefCell<Option<(String, Option<String>)>> = const { RefCell::new(None) };
 }
 
-/// Read start time from pipe
-#[inline]
-pub fn read_start_time_from_pipe(fd: &mut Recver) -> Result<SystemTime, ProcessFunError> {
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Reading start time from pipe");
-
-    let mut buffer = [0u8; SYSTEM_TIME_SIZE];
-    fd.read_exact(&mut buffer)?;
-    let start_time: SystemTime = bytes_to_system_time_unsafe(buffer);
-
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Read start time from pipe");
-
-    Ok(start_time)
-}
-
-/// Read data from a pipe
-#[inline]
-pub fn read_from_pipe(fd: &mut Recver) -> Result<Vec<u8>, ProcessFunError> {
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Starting to read from pipe");
-
-    let mut buffer = vec![];
-    #[allow(unused_variables)]
-    let bytes_read = fd
-        .read_to_end(&mut buffer)
-        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to read from pipe: {}", e)))?;
-
-    #[cfg(feature = "debug")]
-    eprintln!("[process-fun-debug] Read {} bytes from pipe", bytes_read);
-
-    Ok(buffer)
+/// Run the function body in the child, catching a panic and converting it into
+/// `ProcessFunError::ChildPanicked` so it can be sent back to the parent.
+///
+/// A panic hook is installed which records the source location and, when enabled through
+/// `RUST_BACKTRACE`, a backtrace. The previously installed hook is still invoked so the panic
+/// message is printed as usual.
+pub fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, ProcessFunError> {
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info.location().map(|l| l.to_string());
+        let backtrace = Backtrace::capture();
+        let backtrace = match backtrace.status() {
+            BacktraceStatus::Captured => Some(backtrace.to_string()),
+            _ => None,
+        };
+        PANIC_DETAILS.with(|details| {
+            *details.borrow_mut() = location.map(|location| (location, backtrace));
+        });
+        previous_hook(info);
+    }));
+
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "Box<dyn Any>".to_string()
+        };
+        let (location, backtrace) = PANIC_DETAILS
+            .with(|details| details.borrow_mut().take())
+            .map_or((None, None), |(location, backtrace)| {
+                (Some(location), backtrace)
+            });
+
+        #[cfg(feature = "debug")]
+        eprintln!("[process-fun-debug] Child panicked: {}", message);
+
+        ProcessFunError::ChildPanicked {
+            message,
+            location,
+            backtrace,
+        }
+    })
 }
 
 /// Fork the current process and return ForkResult
@@ -328,6 +882,10 @@ pub fn fork_process() -> Result<ForkResult, ProcessFunError> {
     #[cfg(feature = "debug")]
     eprintln!("[process-fun-debug] Forking process");
 
+    // Remembered so that the child can detect that its parent died before it could set up
+    // a parent-death signal
+    child::record_parent();
+
     let result = unsafe {
         fork().map_err(|e| ProcessFunError::ProcessError(format!("Failed to fork process: {}", e)))
     };
@@ -378,6 +936,10 @@ pub enum ProcessFunError {
     #[error("Process communication error: {0}")]
     ProcessError(String),
 
+    /// The child sent data that does not follow the wire protocol, e.g. a truncated frame
+    #[error("Invalid data received from child process: {0}")]
+    ProtocolError(String),
+
     /// serialization/deserialization error for function arguments or results
     #[error("Failed to serialize or deserialize: {0}")]
     SerError(String),
@@ -385,6 +947,70 @@ pub enum ProcessFunError {
     /// Process execution timed out
     #[error("Process execution timed out")]
     TimeoutError,
+
+    /// The other side of a `ProcessChannel` was closed, e.g. because the function returned
+    #[error("The other side of the channel was closed")]
+    ChannelClosed,
+
+    /// The child process exited without returning a result
+    #[error("Child process exited with code {code} without returning a result")]
+    ChildExited { code: i32 },
+
+    /// The child process was terminated by a signal, e.g. a crash or being OOM-killed
+    #[error("Child process was terminated by signal {}{}", signal_name(*signal), if *core_dumped { " (core dumped)" } else { "" })]
+    ChildSignaled { signal: i32, core_dumped: bool },
+
+    /// The child process exceeded its CPU time limit
+    #[error("Child process exceeded its CPU time limit")]
+    CpuLimitExceeded,
+
+    /// The child process exceeded its file size limit
+    #[error("Child process exceeded its file size limit")]
+    FileSizeLimitExceeded,
+
+    /// The child process made a system call its seccomp filter does not allow
+    #[error("Child process made the forbidden system call {syscall}")]
+    SandboxViolation { syscall: String },
+
+    /// Filesystem access was to be restricted, but the kernel does not support Landlock
+    #[error("Cannot restrict filesystem access, the kernel does not support Landlock")]
+    LandlockUnsupported,
+
+    /// The child process was killed by the OOM killer of its cgroup
+    #[error("Child process exceeded its memory limit and was killed")]
+    OutOfMemory,
+
+    /// The function panicked inside the child process
+    #[error("Child process panicked at {}: {message}", location.as_deref().unwrap_or("<unknown>"))]
+    ChildPanicked {
+        message: String,
+        location: Option<String>,
+        backtrace: Option<String>,
+    },
+}
+
+fn signal_name(signal: i32) -> String {
+    Signal::try_from(signal)
+        .map(|s| s.as_str().to_string())
+        .unwrap_or_else(|_| signal.to_string())
+}
+
+impl From<WaitStatus> for ProcessFunError {
+    fn from(status: WaitStatus) -> Self {
+        match status {
+            WaitStatus::Exited(_, code) => ProcessFunError::ChildExited { code },
+            // Only sent by the kernel when a resource limit is exceeded
+            WaitStatus::Signaled(_, Signal::SIGXCPU, _) => ProcessFunError::CpuLimitExceeded,
+            WaitStatus::Signaled(_, Signal::SIGXFSZ, _) => ProcessFunError::FileSizeLimitExceeded,
+            WaitStatus::Signaled(_, signal, core_dumped) => ProcessFunError::ChildSignaled {
+                signal: signal as i32,
+                core_dumped,
+            },
+            status => {
+                ProcessFunError::ProcessError(format!("Unexpected child status: {:?}", status))
+            }
+        }
+    }
 }
 
 impl From<bincode::Error> for ProcessFunError {
@@ -0,0 +1,194 @@
+//! Isolation of the child in Linux namespaces.
+//!
+//! The user, network, mount and IPC namespaces are entered with `unshare` while the child is
+//! set up, before it sends its start time or starts any thread. A new PID namespace only
+//! applies to the children of the caller, so the child forks once more after the handshake.
+//! The process the parent knows, and identifies by its PID and start time, stays outside the
+//! namespace. It waits for the function to run as PID 1 of the namespace and exits the same
+//! way, so the parent sees the outcome and exit status of the function as usual.
+
+use crate::ProcessFunError;
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::prctl;
+use nix::sys::signal::{kill, signal, sigprocmask, SigHandler, SigSet, SigmaskHow, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, getpid, ForkResult, Pid};
+use std::fs;
+
+/// Bit of `CAP_SYS_ADMIN` in the capability sets of `/proc/<pid>/status`
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// The namespaces a child is isolated in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Namespaces {
+    pub(crate) user: bool,
+    pub(crate) network: bool,
+    pub(crate) mount: bool,
+    pub(crate) ipc: bool,
+    pub(crate) pid: bool,
+}
+
+impl Namespaces {
+    /// Create options that keep the child in the namespaces of its parent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enter a new user namespace, in which the user and group of the child are mapped to
+    /// themselves. It is also entered when other namespaces are requested by a process without
+    /// `CAP_SYS_ADMIN`, as long as unprivileged user namespaces are available.
+    pub fn user(mut self) -> Self {
+        self.user = true;
+        self
+    }
+
+    /// Enter a new network namespace, which only has a loopback interface that is down, so
+    /// the function has no network access at all
+    pub fn network(mut self) -> Self {
+        self.network = true;
+        self
+    }
+
+    /// Enter a new mount namespace, so mounts made by the function are private to it
+    pub fn mount(mut self) -> Self {
+        self.mount = true;
+        self
+    }
+
+    /// Enter a new IPC namespace, with its own System V IPC objects and POSIX message queues
+    pub fn ipc(mut self) -> Self {
+        self.ipc = true;
+        self
+    }
+
+    /// Run the function as PID 1 of a new PID namespace, so it cannot see or signal other
+    /// processes. Together with a mount namespace, a fresh `/proc` is mounted for it.
+    pub fn pid(mut self) -> Self {
+        self.pid = true;
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Enter all namespaces but the PID namespace. Must be called while the child has a single
+/// thread.
+pub(crate) fn unshare_namespaces(namespaces: &Namespaces) -> Result<(), ProcessFunError> {
+    if namespaces.is_empty() {
+        return Ok(());
+    }
+
+    let mut flags = CloneFlags::empty();
+    flags.set(CloneFlags::CLONE_NEWNET, namespaces.network);
+    flags.set(CloneFlags::CLONE_NEWNS, namespaces.mount);
+    flags.set(CloneFlags::CLONE_NEWIPC, namespaces.ipc);
+    let user = namespaces.user || !has_cap_sys_admin();
+    flags.set(CloneFlags::CLONE_NEWUSER, user);
+
+    // SAFETY: these calls cannot fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    unshare(flags).map_err(|e| namespace_error("enter namespaces", e))?;
+
+    if user {
+        // Writing the group map without privileges requires denying `setgroups`
+        match fs::write("/proc/self/setgroups", "deny") {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(namespace_error("deny setgroups", e))
+            }
+            _ => {}
+        }
+        fs::write("/proc/self/uid_map", format!("{} {} 1", uid, uid))
+            .map_err(|e| namespace_error("map user", e))?;
+        fs::write("/proc/self/gid_map", format!("{} {} 1", gid, gid))
+            .map_err(|e| namespace_error("map group", e))?;
+    }
+
+    if namespaces.mount {
+        // Keep mounts made by the function from propagating to the parent's namespace
+        mount(
+            None::<&str>,
+            "/",
+            None::<&str>,
+            MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .map_err(|e| namespace_error("make mounts private", e))?;
+    }
+    Ok(())
+}
+
+/// Fork the process that runs the function as PID 1 of a new PID namespace.
+///
+/// Returns `None` in the new process and its PID in the calling process, which must wait for
+/// it with `mirror_exit`.
+pub(crate) fn fork_pid_namespace(namespaces: &Namespaces) -> Result<Option<Pid>, ProcessFunError> {
+    unshare(CloneFlags::CLONE_NEWPID).map_err(|e| namespace_error("enter PID namespace", e))?;
+
+    // SAFETY: the child only continues with the setup of the process function
+    match unsafe { fork() }.map_err(|e| namespace_error("fork into PID namespace", e))? {
+        ForkResult::Parent { child } => Ok(Some(child)),
+        ForkResult::Child => {
+            // Terminated along with the process the parent knows
+            prctl::set_pdeathsig(Signal::SIGKILL)
+                .map_err(|e| namespace_error("set parent-death signal", e))?;
+            if namespaces.mount {
+                mount(
+                    Some("proc"),
+                    "/proc",
+                    Some("proc"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                    None::<&str>,
+                )
+                .map_err(|e| namespace_error("mount /proc", e))?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Wait for the process running the function and exit the same way
+pub(crate) fn mirror_exit(function: Pid) -> ! {
+    let status = loop {
+        match waitpid(function, None) {
+            Err(Errno::EINTR) => continue,
+            status => break status,
+        }
+    };
+
+    // SAFETY: exiting without running destructors, which belong to the function's process
+    let exit = |code| unsafe { libc::_exit(code) };
+    match status {
+        Ok(WaitStatus::Exited(_, code)) => exit(code),
+        Ok(WaitStatus::Signaled(_, signal_number, _)) => {
+            // SAFETY: restoring the default action of a signal
+            let _ = unsafe { signal(signal_number, SigHandler::SigDfl) };
+            let mut signals = SigSet::empty();
+            signals.add(signal_number);
+            let _ = sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&signals), None);
+            let _ = kill(getpid(), signal_number);
+            exit(128 + signal_number as i32)
+        }
+        _ => exit(1),
+    }
+}
+
+/// Whether the calling process may create namespaces other than user namespaces
+fn has_cap_sys_admin() -> bool {
+    let Ok(status) = fs::read_to_string("/proc/self/status") else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+fn namespace_error(action: &str, e: impl std::fmt::Display) -> ProcessFunError {
+    ProcessFunError::ProcessError(format!("Failed to {}: {}", action, e))
+}
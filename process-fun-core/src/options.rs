@@ -0,0 +1,364 @@
+//! Per-call configuration of process functions.
+//!
+//! The `#[process(...)]` attribute is translated into a `ProcessOptions` value, so everything
+//! that can be set through the attribute can also be set at runtime.
+
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options controlling how a process function is run
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    pub(crate) termination: TerminationPolicy,
+    pub(crate) process_group: ProcessGroup,
+    pub(crate) parent_death_signal: Option<Signal>,
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) limits: ResourceLimits,
+    pub(crate) privileges: crate::Privileges,
+    pub(crate) seccomp: Option<crate::SeccompFilter>,
+    pub(crate) fs_access: crate::FsAccess,
+    pub(crate) namespaces: crate::Namespaces,
+    pub(crate) stdout: crate::OutputMode,
+    pub(crate) stderr: crate::OutputMode,
+    pub(crate) env_clear: bool,
+    /// Variables to set, or to remove if the value is `None`, in order
+    pub(crate) env: Vec<(OsString, Option<OsString>)>,
+    pub(crate) current_dir: Option<PathBuf>,
+    pub(crate) umask: Option<u32>,
+    pub(crate) codec: Option<crate::CodecKind>,
+    pub(crate) shared_memory: Option<usize>,
+    #[cfg(feature = "cgroup")]
+    pub(crate) cgroup: Option<crate::CgroupLimits>,
+}
+
+impl ProcessOptions {
+    /// Create options with the default settings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set how the child is terminated by `abort`, `timeout` and drop
+    pub fn termination(mut self, policy: TerminationPolicy) -> Self {
+        self.termination = policy;
+        self
+    }
+
+    /// Set which process group the child is placed in
+    pub fn process_group(mut self, process_group: ProcessGroup) -> Self {
+        self.process_group = process_group;
+        self
+    }
+
+    /// Send `signal` to the child when its parent dies, e.g. because it crashed or was killed.
+    ///
+    /// The signal is sent when the thread that called the process function exits, so the
+    /// process function should be called from a long-lived thread.
+    pub fn parent_death_signal(mut self, signal: Signal) -> Self {
+        self.parent_death_signal = Some(signal);
+        self
+    }
+
+    /// Make the child kill itself (and its process group, if it leads one) once `deadline` has
+    /// passed since it was started, even if no parent is left to enforce a timeout
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set the resource limits applied to the child before the function runs
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the user, groups and capabilities the function runs with
+    pub fn privileges(mut self, privileges: crate::Privileges) -> Self {
+        self.privileges = privileges;
+        self
+    }
+
+    /// Restrict the system calls the function may make to those allowed by `filter`
+    pub fn seccomp(mut self, filter: crate::SeccompFilter) -> Self {
+        self.seccomp = Some(filter);
+        self
+    }
+
+    /// Restrict the paths the function may read and write with Landlock
+    pub fn fs_access(mut self, access: crate::FsAccess) -> Self {
+        self.fs_access = access;
+        self
+    }
+
+    /// Isolate the child in the given Linux namespaces
+    pub fn namespaces(mut self, namespaces: crate::Namespaces) -> Self {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Set where the stdout of the child goes
+    pub fn stdout(mut self, mode: crate::OutputMode) -> Self {
+        self.stdout = mode;
+        self
+    }
+
+    /// Set where the stderr of the child goes
+    pub fn stderr(mut self, mode: crate::OutputMode) -> Self {
+        self.stderr = mode;
+        self
+    }
+
+    /// Set the environment variable `key` in the child
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), Some(value.into())));
+        self
+    }
+
+    /// Remove the environment variable `key` in the child
+    pub fn env_remove(mut self, key: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), None));
+        self
+    }
+
+    /// Start the child with an empty environment, containing only the variables set with
+    /// `env` afterwards
+    pub fn env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// Change the current directory of the child to `dir`
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Set the file mode creation mask of the child, e.g. `0o077`
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    /// Serialize the values exchanged with the child with `codec` instead of the default
+    /// codec, see `codec::set_default`
+    pub fn codec(mut self, codec: crate::CodecKind) -> Self {
+        self.codec = Some(codec);
+        self
+    }
+
+    /// Transfer results whose serialized size reaches `threshold` bytes through a sealed
+    /// memfd shared with the child instead of the pipe
+    pub fn shared_memory(mut self, threshold: usize) -> Self {
+        self.shared_memory = Some(threshold);
+        self
+    }
+
+    /// The codec of the values exchanged with the child
+    pub fn codec_kind(&self) -> crate::CodecKind {
+        self.codec.unwrap_or_else(crate::codec::default_codec)
+    }
+
+    /// Place the child in its own cgroup with the given limits
+    #[cfg(feature = "cgroup")]
+    pub fn cgroup(mut self, limits: crate::CgroupLimits) -> Self {
+        self.cgroup = Some(limits);
+        self
+    }
+
+    /// Whether the child will lead its own process group, so that signals can be sent to the
+    /// whole group
+    pub(crate) fn leads_process_group(&self) -> bool {
+        match self.process_group {
+            ProcessGroup::Inherit => false,
+            ProcessGroup::New => !crate::child::in_process_function(),
+            ProcessGroup::Session => true,
+        }
+    }
+}
+
+/// The process group a child is placed in right after it is forked.
+///
+/// A child leading its own process group is terminated together with everything it spawned,
+/// including nested process functions. Only a child that is still running when it is
+/// terminated takes its group along: once it has exited and was reaped, its process group id
+/// may be reused, so processes it left behind are not signaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProcessGroup {
+    /// Stay in the process group of the parent. Only the child itself is signaled.
+    Inherit,
+    /// Start a new process group. Process functions called from within a process function
+    /// stay in the group of the outer one, so terminating it terminates them as well.
+    ///
+    /// Note that the child no longer receives signals sent to the foreground process group of
+    /// the terminal, e.g. `SIGINT` on Ctrl-C.
+    #[default]
+    New,
+    /// Start a new session, detaching the child from the controlling terminal
+    Session,
+}
+
+/// Resource limits applied to the child with `setrlimit` before the function runs.
+///
+/// Limits that are not set are inherited from the parent. A limit cannot be raised above the
+/// hard limit of the parent unless the process is privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResourceLimits {
+    pub(crate) address_space: Option<u64>,
+    pub(crate) cpu_time: Option<Duration>,
+    pub(crate) nofile: Option<u64>,
+    pub(crate) file_size: Option<u64>,
+    pub(crate) nproc: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Create limits that inherit everything from the parent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the virtual memory of the child to `bytes` (`RLIMIT_AS`). Allocations beyond it
+    /// fail, which aborts the child.
+    pub fn address_space(mut self, bytes: u64) -> Self {
+        self.address_space = Some(bytes);
+        self
+    }
+
+    /// Limit the CPU time of the child (`RLIMIT_CPU`), rounded up to whole seconds. Exceeding
+    /// it is reported as `ProcessFunError::CpuLimitExceeded`.
+    pub fn cpu_time(mut self, cpu_time: Duration) -> Self {
+        self.cpu_time = Some(cpu_time);
+        self
+    }
+
+    /// Limit the number of files the child can have open (`RLIMIT_NOFILE`)
+    pub fn nofile(mut self, count: u64) -> Self {
+        self.nofile = Some(count);
+        self
+    }
+
+    /// Limit the size of files the child writes to `bytes` (`RLIMIT_FSIZE`). Exceeding it is
+    /// reported as `ProcessFunError::FileSizeLimitExceeded`.
+    pub fn file_size(mut self, bytes: u64) -> Self {
+        self.file_size = Some(bytes);
+        self
+    }
+
+    /// Limit the number of processes and threads of the user running the child
+    /// (`RLIMIT_NPROC`). The limit counts all processes of the user and does not apply to
+    /// privileged processes.
+    pub fn nproc(mut self, count: u64) -> Self {
+        self.nproc = Some(count);
+        self
+    }
+}
+
+/// Time a child is given to exit after the signal of `#[process(terminate(signal = ...))]`
+/// unless a grace period is given as well
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How a child process is terminated when it is aborted, times out or its wrapper is dropped.
+///
+/// `signal` is sent first. If the child has not exited after `grace_period`, it is killed
+/// with `SIGKILL`. The default sends `SIGKILL` right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminationPolicy {
+    /// Signal sent to ask the child to exit
+    pub signal: Signal,
+    /// Time the child is given to exit before it is killed with `SIGKILL`
+    pub grace_period: Duration,
+}
+
+impl TerminationPolicy {
+    /// Kill the child with `SIGKILL` right away
+    pub fn kill() -> Self {
+        Self {
+            signal: Signal::SIGKILL,
+            grace_period: Duration::ZERO,
+        }
+    }
+
+    /// Send `signal` and escalate to `SIGKILL` if the child is still running after
+    /// `grace_period`
+    pub fn graceful(signal: Signal, grace_period: Duration) -> Self {
+        Self {
+            signal,
+            grace_period,
+        }
+    }
+}
+
+impl Default for TerminationPolicy {
+    fn default() -> Self {
+        Self::kill()
+    }
+}
+
+/// How a child process ended when it was terminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Termination {
+    /// The child had already exited on its own
+    AlreadyExited,
+    /// The child exited after the signal of the termination policy
+    Graceful,
+    /// The child was killed with `SIGKILL`
+    Killed,
+}
+
+/// Parse a size such as `"512K"`, `"64M"` or `"1G"`. Units are powers of 1024 and may be
+/// followed by `B` or `iB`, e.g. `"1GiB"`.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", text))?;
+    let unit = unit.trim();
+    let unit = unit
+        .strip_suffix("iB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(unit);
+    let shift = match unit {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("Unknown unit in size '{}'", text)),
+    };
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("Size '{}' is too large", text))
+}
+
+/// Parse a duration such as `"500ms"`, `"30s"`, `"5m"` or `"1h"`
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("Missing unit in duration '{}'", text))?;
+    let (value, unit) = text.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("Invalid duration '{}'", text))?;
+    let seconds = |factor: u64| {
+        value
+            .checked_mul(factor)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("Duration '{}' is too large", text))
+    };
+    match unit.trim() {
+        "ns" => Ok(Duration::from_nanos(value)),
+        "us" => Ok(Duration::from_micros(value)),
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => seconds(60),
+        "h" => seconds(60 * 60),
+        unit => Err(format!("Unknown unit '{}' in duration '{}'", unit, text)),
+    }
+}
@@ -0,0 +1,237 @@
+//! Redirection and capture of the standard output and error of children.
+//!
+//! Pipes for captured streams are created before the fork. The child moves their write ends
+//! onto its stdout and stderr, and opens `/dev/null` or the output file itself. In the parent,
+//! a thread per captured stream drains the pipe while the child runs, so the child never
+//! blocks on a full pipe. Processes the child spawned may keep the pipe open after it exited,
+//! so once the child has been reaped the thread only takes what is already in the pipe.
+
+use crate::{ProcessFunError, ProcessOptions};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::unistd::{dup2, pipe2, read};
+use std::fs::File;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+
+/// Bytes kept of a captured stream unless another limit is given
+pub const DEFAULT_CAPTURE_LIMIT: usize = 1 << 20;
+
+/// Where the child's stdout or stderr goes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Write to the stream of the parent
+    #[default]
+    Inherit,
+    /// Discard everything written
+    Null,
+    /// Capture into memory, keeping the first `limit` bytes. Anything beyond is discarded and
+    /// the output is marked as truncated.
+    Capture { limit: usize },
+    /// Write to a file, which is created or truncated before the function runs
+    File(PathBuf),
+}
+
+impl OutputMode {
+    /// Capture into memory, keeping at most `DEFAULT_CAPTURE_LIMIT` bytes
+    pub fn capture() -> Self {
+        Self::Capture {
+            limit: DEFAULT_CAPTURE_LIMIT,
+        }
+    }
+}
+
+/// What was captured from a stream of the child. Empty if the stream was not captured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapturedOutput {
+    pub bytes: Vec<u8>,
+    /// Whether more was written than the capture limit allowed to keep
+    pub truncated: bool,
+}
+
+impl CapturedOutput {
+    fn append(&mut self, bytes: &[u8], limit: usize) {
+        let kept = bytes.len().min(limit.saturating_sub(self.bytes.len()));
+        self.bytes.extend_from_slice(&bytes[..kept]);
+        self.truncated |= kept < bytes.len();
+    }
+}
+
+/// The result of a process function along with its captured output
+#[derive(Debug)]
+pub struct ProcessOutput<T> {
+    pub result: Result<T, ProcessFunError>,
+    pub stdout: CapturedOutput,
+    pub stderr: CapturedOutput,
+}
+
+/// The redirections of the child's stdout and stderr, prepared before the fork
+#[derive(Debug, Default)]
+pub struct OutputPipes {
+    stdout: Redirection,
+    stderr: Redirection,
+}
+
+#[derive(Debug, Default)]
+enum Redirection {
+    #[default]
+    Inherit,
+    Null,
+    File(PathBuf),
+    Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+        limit: usize,
+    },
+}
+
+impl Redirection {
+    fn new(mode: &OutputMode) -> Result<Self, ProcessFunError> {
+        Ok(match mode {
+            OutputMode::Inherit => Self::Inherit,
+            OutputMode::Null => Self::Null,
+            OutputMode::File(path) => Self::File(path.clone()),
+            &OutputMode::Capture { limit } => {
+                let (read, write) = pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+                    ProcessFunError::ProcessError(format!("Failed to create output pipe: {}", e))
+                })?;
+                Self::Pipe { read, write, limit }
+            }
+        })
+    }
+
+    /// Make `target` write where this redirection points, in the child
+    fn apply(self, target: RawFd) -> Result<(), ProcessFunError> {
+        let redirect = |fd: &dyn AsRawFd| {
+            dup2(fd.as_raw_fd(), target).map(drop).map_err(|e| {
+                ProcessFunError::ProcessError(format!("Failed to redirect output: {}", e))
+            })
+        };
+        let open_error = |path: &str, e| {
+            ProcessFunError::ProcessError(format!("Failed to open {}: {}", path, e))
+        };
+
+        match self {
+            Self::Inherit => Ok(()),
+            Self::Null => redirect(
+                &File::options()
+                    .write(true)
+                    .open("/dev/null")
+                    .map_err(|e| open_error("/dev/null", e))?,
+            ),
+            Self::File(path) => redirect(
+                &File::create(&path).map_err(|e| open_error(&path.display().to_string(), e))?,
+            ),
+            Self::Pipe { write, .. } => redirect(&write),
+        }
+    }
+
+    /// Start draining the pipe of a captured stream, in the parent
+    fn capture(self) -> Result<Option<Reader>, ProcessFunError> {
+        let Self::Pipe { read, limit, .. } = self else {
+            return Ok(None);
+        };
+        let (stop_read, stop) = pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to create output pipe: {}", e))
+        })?;
+        let handle = std::thread::Builder::new()
+            .name("process-fun-output".to_string())
+            .spawn(move || drain(read, stop_read, limit))
+            .map_err(|e| {
+                ProcessFunError::ProcessError(format!("Failed to start output reader: {}", e))
+            })?;
+        Ok(Some(Reader { stop, handle }))
+    }
+}
+
+impl OutputPipes {
+    /// Prepare the redirections requested by `options`
+    pub fn new(options: &ProcessOptions) -> Result<Self, ProcessFunError> {
+        Ok(Self {
+            stdout: Redirection::new(&options.stdout)?,
+            stderr: Redirection::new(&options.stderr)?,
+        })
+    }
+
+    /// Redirect the stdout and stderr of the child. Must be called in the child.
+    pub fn redirect(self) -> Result<(), ProcessFunError> {
+        self.stdout.apply(libc::STDOUT_FILENO)?;
+        self.stderr.apply(libc::STDERR_FILENO)
+    }
+
+    /// Start capturing the streams that are piped to the parent. Must be called in the parent.
+    pub(crate) fn capture(self) -> Result<Capture, ProcessFunError> {
+        Ok(Capture {
+            stdout: self.stdout.capture()?,
+            stderr: self.stderr.capture()?,
+        })
+    }
+}
+
+/// The threads capturing the output of a child in the parent
+#[derive(Debug, Default)]
+pub(crate) struct Capture {
+    stdout: Option<Reader>,
+    stderr: Option<Reader>,
+}
+
+impl Capture {
+    /// Collect everything captured. Must only be called once the child has been reaped.
+    pub(crate) fn finish(&mut self) -> (CapturedOutput, CapturedOutput) {
+        (
+            self.stdout.take().map(Reader::finish).unwrap_or_default(),
+            self.stderr.take().map(Reader::finish).unwrap_or_default(),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Reader {
+    /// Closed to make the thread stop once the pipe is empty
+    stop: OwnedFd,
+    handle: JoinHandle<CapturedOutput>,
+}
+
+impl Reader {
+    fn finish(self) -> CapturedOutput {
+        drop(self.stop);
+        self.handle.join().unwrap_or_default()
+    }
+}
+
+/// Read `pipe` until it is closed, or until `stop` is closed and the pipe is empty
+fn drain(pipe: OwnedFd, stop: OwnedFd, limit: usize) -> CapturedOutput {
+    let mut output = CapturedOutput::default();
+    if fcntl(pipe.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).is_err() {
+        return output;
+    }
+
+    let mut buffer = [0u8; 8192];
+    loop {
+        let mut fds = [
+            PollFd::new(pipe.as_fd(), PollFlags::POLLIN),
+            PollFd::new(stop.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut fds, PollTimeout::NONE) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return output,
+        }
+        let stopping = fds[1].revents().is_some_and(|events| !events.is_empty());
+
+        loop {
+            match read(pipe.as_raw_fd(), &mut buffer) {
+                Ok(0) => return output,
+                Ok(n) => output.append(&buffer[..n], limit),
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => break,
+                Err(_) => return output,
+            }
+        }
+        if stopping {
+            return output;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Process file descriptors (pidfds), which refer to a specific process rather than a PID
+//! that may be reused, and become readable once the process exits.
+//!
+//! The pidfd is opened with `pidfd_open` right after `fork` returns in the parent. Until the
+//! child is reaped, which only process-fun does, its PID cannot be reused, so this is as
+//! reliable as `clone3` with `CLONE_PIDFD` while keeping the fork handlers of libc intact.
+
+use crate::report::{self, Exit};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::Signal;
+use nix::sys::wait::WaitPidFlag;
+use nix::unistd::Pid;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+
+/// Open a pidfd for `pid`, or `None` if the kernel does not support pidfds
+pub(crate) fn open(pid: Pid) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    if fd < 0 {
+        #[cfg(feature = "debug")]
+        eprintln!(
+            "[process-fun-debug] pidfd_open failed for {}: {}",
+            pid,
+            nix::errno::Errno::last()
+        );
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Send `signal` to the process, or only check that it has not been reaped yet if `signal`
+/// is `None`. Fails with `ESRCH` once the process has been reaped.
+pub(crate) fn send_signal(pidfd: BorrowedFd<'_>, signal: Option<Signal>) -> Result<(), Errno> {
+    let signal = signal.map_or(0, |signal| signal as libc::c_int);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal,
+            ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    Errno::result(result).map(drop)
+}
+
+/// Wait for the process to exit and reap it. Falls back to `wait4` on kernels that support
+/// pidfds but cannot wait on them yet.
+pub(crate) fn wait(pidfd: BorrowedFd<'_>, pid: Pid, nohang: bool) -> Result<Exit, Errno> {
+    let flags = if nohang {
+        WaitPidFlag::WNOHANG
+    } else {
+        WaitPidFlag::empty()
+    };
+    match report::waitid_pidfd(pidfd.as_raw_fd(), flags) {
+        Err(Errno::EINVAL) => report::wait4(pid, flags),
+        result => result,
+    }
+}
@@ -0,0 +1,268 @@
+//! Dropping the privileges of the child before the function runs.
+//!
+//! The child drops capabilities from its bounding set while it may still do so, switches its
+//! groups and user, and then clears the dropped capabilities from its effective, permitted,
+//! inheritable and ambient sets. Switching from root to another user clears all of those sets
+//! anyway. Any step that fails is reported to the parent instead of running the function with
+//! more privileges than requested.
+
+use crate::ProcessFunError;
+use nix::libc;
+use nix::libc::{c_int, c_ulong};
+use nix::sys::prctl;
+use nix::unistd::{setgroups, setresgid, setresuid, Gid, Uid};
+
+/// `_LINUX_CAPABILITY_VERSION_3`, whose sets span two 32-bit words
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Names of the capabilities, indexed by their number
+const CAPABILITIES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+/// `struct __user_cap_header_struct`
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int,
+}
+
+/// `struct __user_cap_data_struct`
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The privileges a process function runs with.
+///
+/// By default the child keeps the user, groups and capabilities of its parent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Privileges {
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) groups: Option<Vec<u32>>,
+    pub(crate) drop_capabilities: Vec<String>,
+    pub(crate) drop_all_capabilities: bool,
+    pub(crate) no_new_privs: bool,
+}
+
+impl Privileges {
+    /// Create options that keep the privileges of the parent
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switch the real, effective and saved user ID to `uid`
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Switch the real, effective and saved group ID to `gid`
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Set the supplementary groups. If the user or group is switched without setting them,
+    /// the supplementary groups are cleared.
+    pub fn groups(mut self, groups: impl IntoIterator<Item = u32>) -> Self {
+        self.groups = Some(groups.into_iter().collect());
+        self
+    }
+
+    /// Drop the capability `name`, e.g. `"CAP_NET_RAW"` or `"net_raw"`, from the bounding,
+    /// effective, permitted, inheritable and ambient sets
+    pub fn drop_capability(mut self, name: impl Into<String>) -> Self {
+        self.drop_capabilities.push(name.into());
+        self
+    }
+
+    /// Drop every capability, so neither the function nor any program it executes can gain one
+    pub fn drop_all_capabilities(mut self) -> Self {
+        self.drop_all_capabilities = true;
+        self
+    }
+
+    /// Set `PR_SET_NO_NEW_PRIVS`, so programs executed by the function cannot gain privileges
+    /// through set-user-ID bits or file capabilities
+    pub fn no_new_privs(mut self) -> Self {
+        self.no_new_privs = true;
+        self
+    }
+}
+
+/// Drop the privileges of the calling child according to `privileges`
+pub(crate) fn drop_privileges(privileges: &Privileges) -> Result<(), ProcessFunError> {
+    let dropped = if privileges.drop_all_capabilities {
+        (0..CAPABILITIES.len()).collect()
+    } else {
+        privileges
+            .drop_capabilities
+            .iter()
+            .map(|name| {
+                capability_number(name).ok_or_else(|| {
+                    ProcessFunError::ProcessError(format!("Unknown capability '{}'", name))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Requires CAP_SETPCAP, which switching the user may take away
+    for &capability in &dropped {
+        // SAFETY: reading and dropping from the bounding set take no pointers
+        let in_bounding_set =
+            unsafe { libc::prctl(libc::PR_CAPBSET_READ, capability as c_ulong, 0, 0, 0) };
+        // Capabilities unknown to the running kernel are not in any set
+        if in_bounding_set == 1
+            && unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability as c_ulong, 0, 0, 0) } != 0
+        {
+            return Err(privilege_error(
+                &format!(
+                    "drop CAP_{} from the bounding set",
+                    capability_label(capability)
+                ),
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    let switches_identity = privileges.uid.is_some() || privileges.gid.is_some();
+    let groups = match &privileges.groups {
+        Some(groups) => Some(groups.iter().copied().map(Gid::from_raw).collect()),
+        None if switches_identity => Some(vec![]),
+        None => None,
+    };
+    if let Some(groups) = groups {
+        setgroups(&groups).map_err(|e| privilege_error("set supplementary groups", e))?;
+    }
+    if let Some(gid) = privileges.gid.map(Gid::from_raw) {
+        setresgid(gid, gid, gid)
+            .map_err(|e| privilege_error(&format!("switch to group {}", gid), e))?;
+    }
+    if let Some(uid) = privileges.uid.map(Uid::from_raw) {
+        setresuid(uid, uid, uid)
+            .map_err(|e| privilege_error(&format!("switch to user {}", uid), e))?;
+    }
+
+    if !dropped.is_empty() {
+        clear_capabilities(&dropped)?;
+    }
+
+    if privileges.no_new_privs {
+        prctl::set_no_new_privs().map_err(|e| privilege_error("set no_new_privs", e))?;
+    }
+    Ok(())
+}
+
+/// Clear `capabilities` from the effective, permitted, inheritable and ambient sets
+fn clear_capabilities(capabilities: &[usize]) -> Result<(), ProcessFunError> {
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    // SAFETY: `header` and `data` match the layout of version 3
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(privilege_error(
+            "read capabilities",
+            std::io::Error::last_os_error(),
+        ));
+    }
+
+    for &capability in capabilities {
+        let (word, bit) = (capability / 32, 1 << (capability % 32));
+        data[word].effective &= !bit;
+        data[word].permitted &= !bit;
+        data[word].inheritable &= !bit;
+
+        // Ambient capabilities are also lowered with the permitted set, but only on `execve`
+        // SAFETY: lowering an ambient capability takes no pointers
+        let result = unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_LOWER as c_ulong,
+                capability as c_ulong,
+                0,
+                0,
+            )
+        };
+        // Kernels without ambient capabilities, or not knowing this capability, fail with
+        // EINVAL, and then it cannot be ambient either
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::EINVAL) {
+                return Err(privilege_error("lower ambient capabilities", error));
+            }
+        }
+    }
+
+    // SAFETY: as above
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(privilege_error(
+            "drop capabilities",
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// The number of the capability `name`, with or without the `CAP_` prefix and in any case
+fn capability_number(name: &str) -> Option<usize> {
+    let name = name.to_ascii_lowercase();
+    let name = name.strip_prefix("cap_").unwrap_or(&name);
+    CAPABILITIES
+        .iter()
+        .position(|capability| *capability == name)
+}
+
+fn capability_label(capability: usize) -> String {
+    CAPABILITIES[capability].to_ascii_uppercase()
+}
+
+fn privilege_error(action: &str, e: impl std::fmt::Display) -> ProcessFunError {
+    ProcessFunError::ProcessError(format!("Failed to {}: {}", action, e))
+}
@@ -0,0 +1,15 @@
+//! Progress updates sent by a process function while it runs.
+//!
+//! The function calls [`report`] with any serializable value. The parent takes the updates
+//! with `ProcessWrapper::progress`, e.g. between calls to `wait_timeout`.
+
+use crate::{child, ProcessFunError};
+use serde::Serialize;
+
+/// Send a progress update to the parent.
+///
+/// Nothing is sent when the function is not running as a process function, e.g. when it is
+/// called directly.
+pub fn report<P: Serialize>(value: &P) -> Result<(), ProcessFunError> {
+    child::with_writer(|writer| writer.write_progress(value))
+}
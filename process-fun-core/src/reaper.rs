@@ -0,0 +1,182 @@
+//! Optional background reaper for child processes.
+//!
+//! A `ProcessWrapper` reaps its child when it is waited on, aborted or dropped. A wrapper
+//! that is leaked (e.g. with `mem::forget`) never runs any of these, so its child would stay
+//! a zombie. Calling [`install`] starts a background thread that is woken by `SIGCHLD` and
+//! reaps every child created by process-fun once it exits, storing the exit status and
+//! resource usage where the owning wrapper (if any) can pick them up.
+//!
+//! Only children registered by process-fun are reaped, so children spawned by other means
+//! (e.g. `std::process::Command`) are left alone. Installing the reaper replaces any existing
+//! `SIGCHLD` handler of the process.
+
+use crate::report::{self, Exit};
+use crate::ProcessFunError;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::{getpid, pipe2, read, write, Pid};
+use std::collections::HashMap;
+use std::os::fd::{BorrowedFd, IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+
+/// Process that installed the reaper. Forked children inherit the statics but not the
+/// reaper thread, so the reaper is only active in this process.
+static INSTALLER: AtomicI32 = AtomicI32::new(0);
+
+/// Write end of the self-pipe used to wake the reaper thread
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+
+static CHILDREN: OnceLock<Mutex<HashMap<Pid, Arc<ExitSlot>>>> = OnceLock::new();
+
+/// Exit status and resource usage of a registered child, filled in by the reaper thread
+#[derive(Debug, Default)]
+pub(crate) struct ExitSlot {
+    status: Mutex<Option<Result<Exit, Errno>>>,
+    reaped: Condvar,
+}
+
+impl ExitSlot {
+    /// Block until the reaper thread has collected the exit status
+    pub(crate) fn wait(&self) -> Result<Exit, Errno> {
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if let Some(status) = *status {
+                return status;
+            }
+            status = self.reaped.wait(status).unwrap();
+        }
+    }
+
+    /// The exit status, if the reaper thread has collected it already
+    pub(crate) fn try_get(&self) -> Option<Result<Exit, Errno>> {
+        *self.status.lock().unwrap()
+    }
+
+    /// Run `f` unless the child was already reaped, preventing the reaper from reaping it
+    /// (and its PID from being reused) while `f` runs
+    pub(crate) fn unless_reaped<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        let status = self.status.lock().unwrap();
+        if status.is_some() {
+            None
+        } else {
+            Some(f())
+        }
+    }
+}
+
+/// Install the background reaper for this process.
+///
+/// Only wrappers created after this call are handled by the reaper. Calling it more than once
+/// is a no-op.
+pub fn install() -> Result<(), ProcessFunError> {
+    static INSTALL: Mutex<()> = Mutex::new(());
+    let _guard = INSTALL.lock().unwrap();
+    if is_active() {
+        return Ok(());
+    }
+
+    #[cfg(feature = "debug")]
+    eprintln!("[process-fun-debug] Installing background reaper");
+
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).map_err(|e| {
+        ProcessFunError::ProcessError(format!("Failed to create reaper pipe: {}", e))
+    })?;
+    // The signal handler must never block on a full pipe
+    let write_fd = write_fd.into_raw_fd();
+    fcntl(write_fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(|e| {
+        ProcessFunError::ProcessError(format!("Failed to configure reaper pipe: {}", e))
+    })?;
+    WAKE_FD.store(write_fd, Ordering::SeqCst);
+    let read_fd = read_fd.into_raw_fd();
+
+    std::thread::Builder::new()
+        .name("process-fun-reaper".to_string())
+        .spawn(move || reaper_loop(read_fd))
+        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to start reaper: {}", e)))?;
+
+    let action = SigAction::new(
+        SigHandler::Handler(on_sigchld),
+        SaFlags::SA_RESTART | SaFlags::SA_NOCLDSTOP,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGCHLD, &action) }.map_err(|e| {
+        ProcessFunError::ProcessError(format!("Failed to install SIGCHLD handler: {}", e))
+    })?;
+
+    INSTALLER.store(getpid().as_raw(), Ordering::SeqCst);
+    Ok(())
+}
+
+/// Whether the background reaper is running in this process
+#[inline]
+pub fn is_active() -> bool {
+    INSTALLER.load(Ordering::SeqCst) == getpid().as_raw()
+}
+
+/// Hand a child over to the reaper, returning the slot its exit status will be stored in.
+/// Returns `None` if the reaper is not active.
+pub(crate) fn register(pid: Pid) -> Option<Arc<ExitSlot>> {
+    if !is_active() {
+        return None;
+    }
+
+    let slot = Arc::new(ExitSlot::default());
+    children().lock().unwrap().insert(pid, slot.clone());
+
+    // The child may have exited before it was registered
+    wake();
+    Some(slot)
+}
+
+fn children() -> &'static Mutex<HashMap<Pid, Arc<ExitSlot>>> {
+    CHILDREN.get_or_init(Default::default)
+}
+
+#[inline]
+fn wake() {
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // A full pipe already guarantees a pending wake-up
+        let _ = write(unsafe { BorrowedFd::borrow_raw(fd) }, &[0]);
+    }
+}
+
+extern "C" fn on_sigchld(_: nix::libc::c_int) {
+    let errno = Errno::last_raw();
+    if is_active() {
+        wake();
+    }
+    Errno::set_raw(errno);
+}
+
+fn reaper_loop(read_fd: RawFd) {
+    let mut buffer = [0u8; 64];
+    loop {
+        match read(read_fd, &mut buffer) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(_) => return,
+        }
+
+        children().lock().unwrap().retain(|&pid, slot| {
+            let mut status = slot.status.lock().unwrap();
+            match report::wait4(pid, WaitPidFlag::WNOHANG) {
+                Ok(Exit {
+                    status: WaitStatus::StillAlive,
+                    ..
+                })
+                | Err(Errno::EINTR) => true,
+                result => {
+                    #[cfg(feature = "debug")]
+                    eprintln!("[process-fun-debug] Reaper collected {}: {:?}", pid, result);
+
+                    *status = Some(result);
+                    slot.reaped.notify_all();
+                    false
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,127 @@
+//! Resource usage reports of finished children.
+//!
+//! Children are reaped with `wait4`, or `waitid` on their pidfd, which also return the
+//! resources used by the child and the descendants it waited for. The report combines them
+//! with the time from the fork until the child was reaped, which the background reaper
+//! records as it reaps.
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::time::{Duration, Instant};
+
+/// How much a finished process function used, for benchmarking and accounting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessReport {
+    /// Time from the fork until the child was reaped
+    pub wall_time: Duration,
+    /// CPU time spent in user mode
+    pub user_time: Duration,
+    /// CPU time spent in kernel mode
+    pub sys_time: Duration,
+    /// Peak resident set size in bytes
+    pub max_rss: u64,
+    /// Page faults served without I/O
+    pub minor_faults: u64,
+    /// Page faults that required I/O
+    pub major_faults: u64,
+    /// How the child exited
+    pub exit_status: WaitStatus,
+}
+
+impl ProcessReport {
+    pub(crate) fn new(exit: &Exit, started: Instant) -> Self {
+        let usage = &exit.usage;
+        Self {
+            wall_time: exit.at.saturating_duration_since(started),
+            user_time: to_duration(usage.ru_utime),
+            sys_time: to_duration(usage.ru_stime),
+            // Reported in kilobytes
+            max_rss: usage.ru_maxrss.max(0) as u64 * 1024,
+            minor_faults: usage.ru_minflt.max(0) as u64,
+            major_faults: usage.ru_majflt.max(0) as u64,
+            exit_status: exit.status,
+        }
+    }
+}
+
+/// The outcome of waiting for a child: its status, which is `StillAlive` if it has not
+/// exited yet, and the resources it used
+#[derive(Clone, Copy)]
+pub(crate) struct Exit {
+    pub(crate) status: WaitStatus,
+    pub(crate) usage: libc::rusage,
+    /// When the status was collected
+    pub(crate) at: Instant,
+}
+
+impl std::fmt::Debug for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Exit")
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+/// Wait for `pid` like `waitpid`, collecting its resource usage
+pub(crate) fn wait4(pid: Pid, flags: WaitPidFlag) -> Result<Exit, Errno> {
+    let mut status = 0;
+    // SAFETY: all-zero is a valid `rusage`
+    let mut usage = unsafe { std::mem::zeroed() };
+    // SAFETY: `status` and `usage` are valid for writes
+    let result = unsafe { libc::wait4(pid.as_raw(), &mut status, flags.bits(), &mut usage) };
+    let status = match Errno::result(result)? {
+        0 => WaitStatus::StillAlive,
+        pid => WaitStatus::from_raw(Pid::from_raw(pid), status)?,
+    };
+    Ok(Exit {
+        status,
+        usage,
+        at: Instant::now(),
+    })
+}
+
+/// Wait for the process referred to by `pidfd` to exit, collecting its resource usage
+pub(crate) fn waitid_pidfd(pidfd: libc::c_int, flags: WaitPidFlag) -> Result<Exit, Errno> {
+    // SAFETY: all-zero is a valid `siginfo_t` and `rusage`
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let mut usage = unsafe { std::mem::zeroed() };
+    // The libc wrapper of `waitid` does not return the resource usage
+    // SAFETY: `info` and `usage` are valid for writes
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_waitid,
+            libc::P_PIDFD,
+            pidfd,
+            &mut info,
+            (WaitPidFlag::WEXITED | flags).bits(),
+            &mut usage,
+        )
+    };
+    Errno::result(result)?;
+
+    // SAFETY: the fields of a `SIGCHLD` are set by a successful `waitid`
+    let (pid, status) = unsafe { (info.si_pid(), info.si_status()) };
+    let status = match info.si_code {
+        _ if pid == 0 => WaitStatus::StillAlive,
+        libc::CLD_EXITED => WaitStatus::Exited(Pid::from_raw(pid), status),
+        libc::CLD_KILLED | libc::CLD_DUMPED => WaitStatus::Signaled(
+            Pid::from_raw(pid),
+            Signal::try_from(status)?,
+            info.si_code == libc::CLD_DUMPED,
+        ),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(Exit {
+        status,
+        usage,
+        at: Instant::now(),
+    })
+}
+
+fn to_duration(time: libc::timeval) -> Duration {
+    Duration::from_secs(time.tv_sec.max(0) as u64)
+        + Duration::from_micros(time.tv_usec.max(0) as u64)
+}
@@ -0,0 +1,426 @@
+//! Seccomp-bpf filters restricting the system calls a process function may make.
+//!
+//! The filter is installed in the child after the start-time handshake and applies to all of
+//! its threads. A system call the filter does not allow raises `SIGSYS`. Its handler sends a
+//! `Violation` frame with the number of the system call to the parent and exits the child, so
+//! the call is reported as `ProcessFunError::SandboxViolation` naming the system call.
+
+use crate::{wire, ProcessFunError};
+use nix::libc;
+use nix::libc::{c_int, c_long, c_uint, c_void, sock_filter, sock_fprog};
+use nix::sys::prctl;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::getpid;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Value of `seccomp_data.arch` for the architecture the filter is compiled for
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Bit set in the numbers of x32 system calls, which are denied along with other architectures
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets of the fields of `seccomp_data` loaded by the filter
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+/// Exit code of a child that made a forbidden system call, as for an error result
+const VIOLATION_EXIT_CODE: c_int = 101;
+
+/// System calls needed to report the outcome to the parent and by the deadline watchdog,
+/// which every filter allows
+const ESSENTIAL: &[&str] = &[
+    "close",
+    "futex",
+    "rt_sigreturn",
+    "restart_syscall",
+    "nanosleep",
+    "clock_nanosleep",
+    "exit",
+    "exit_group",
+];
+
+/// System calls of the `compute_only` preset that are only allowed on the file descriptors of
+/// the child
+const COMPUTE_ONLY_ON_FDS: &[&str] = &["read", "readv", "writev", "recvfrom", "sendto"];
+
+/// System calls of the `compute_only` preset
+const COMPUTE_ONLY: &[&str] = &[
+    "ppoll",
+    "brk",
+    "mmap",
+    "munmap",
+    "mremap",
+    "mprotect",
+    "madvise",
+    "sched_yield",
+    "sched_getaffinity",
+    "getrandom",
+    "clock_gettime",
+    "clock_getres",
+    "gettimeofday",
+    "nanosleep",
+    "clock_nanosleep",
+    "getpid",
+    "gettid",
+    "rt_sigaction",
+    "rt_sigprocmask",
+    "sigaltstack",
+];
+
+/// System calls of the `compute_only` preset that only exist on some architectures
+#[cfg(target_arch = "x86_64")]
+const COMPUTE_ONLY_ARCH: &[&str] = &["poll"];
+#[cfg(not(target_arch = "x86_64"))]
+const COMPUTE_ONLY_ARCH: &[&str] = &[];
+
+/// Write end of the pipe to the parent, used by the `SIGSYS` handler
+static VIOLATION_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// The system calls a process function may make.
+///
+/// Every filter allows what the child needs to report its outcome: writing to the pipe and
+/// the shared memory of the call, closing them, exiting and signaling itself. Writing to
+/// standard error is allowed too, so the message of a panic is printed. Sleeping is allowed
+/// as well, for the deadline watchdog. Other system calls are allowed by name, e.g.
+/// `"openat"`, and are checked when the filter is installed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeccompFilter {
+    pub(crate) allowed: Vec<String>,
+    /// System calls only allowed on the pipe, shared memory and channel of the child
+    pub(crate) allowed_on_fds: Vec<String>,
+}
+
+impl SeccompFilter {
+    /// Create a filter allowing only what the child needs to report its outcome
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a filter for pure computation: allocating memory, synchronizing, reading clocks
+    /// and random numbers, sleeping, and exchanging messages through a `ProcessChannel`. Other
+    /// file descriptors cannot be read or written and files cannot be opened, and neither
+    /// threads nor processes can be created.
+    pub fn compute_only() -> Self {
+        let mut filter = COMPUTE_ONLY
+            .iter()
+            .chain(COMPUTE_ONLY_ARCH)
+            .fold(Self::new(), |filter, name| filter.allow(*name));
+        filter.allowed_on_fds = COMPUTE_ONLY_ON_FDS
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        filter
+    }
+
+    /// Also allow the system call `name`, on any file descriptor
+    pub fn allow(mut self, name: impl Into<String>) -> Self {
+        self.allowed.push(name.into());
+        self
+    }
+}
+
+/// A system call allowed by the filter, optionally only if the argument with the given index
+/// has the given value
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+    nr: c_long,
+    arg: Option<(u32, i32)>,
+}
+
+/// Install `filter` for all threads of the calling child. Violations are reported through
+/// `pipe`. System calls allowed on the file descriptors of the child are allowed on `pipe` and
+/// `fds`.
+pub(crate) fn install(
+    filter: &SeccompFilter,
+    pipe: RawFd,
+    fds: &[RawFd],
+) -> Result<(), ProcessFunError> {
+    let number = |name: &str| {
+        syscall_number(name)
+            .ok_or_else(|| ProcessFunError::ProcessError(format!("Unknown system call '{}'", name)))
+    };
+    let mut rules = ESSENTIAL
+        .iter()
+        .copied()
+        .chain(filter.allowed.iter().map(String::as_str))
+        .map(|name| {
+            Ok(Rule {
+                nr: number(name)?,
+                arg: None,
+            })
+        })
+        .collect::<Result<Vec<_>, ProcessFunError>>()?;
+
+    // Writing the outcome and panic messages, and the calls the filter allows on the file
+    // descriptors of the child
+    rules.push(Rule {
+        nr: libc::SYS_write,
+        arg: Some((0, libc::STDERR_FILENO)),
+    });
+    let fds = [pipe].into_iter().chain(fds.iter().copied());
+    for name in ["write"]
+        .into_iter()
+        .chain(filter.allowed_on_fds.iter().map(String::as_str))
+    {
+        let nr = number(name)?;
+        rules.extend(fds.clone().map(|fd| Rule {
+            nr,
+            arg: Some((0, fd)),
+        }));
+    }
+
+    // Signaling itself or its own process group, e.g. by the deadline watchdog or `abort`,
+    // checking that a file descriptor is open before closing it, as debug builds do, and
+    // sealing the shared memory of a large result
+    let pid = getpid().as_raw();
+    for (nr, arg) in [
+        (libc::SYS_kill, (0, pid)),
+        (libc::SYS_kill, (0, -pid)),
+        (libc::SYS_tgkill, (0, pid)),
+        (libc::SYS_fcntl, (1, libc::F_GETFD)),
+        (libc::SYS_fcntl, (1, libc::F_ADD_SEALS)),
+    ] {
+        rules.push(Rule { nr, arg: Some(arg) });
+    }
+
+    let mut program = compile(&rules)?;
+
+    VIOLATION_PIPE.store(pipe, Ordering::SeqCst);
+    let handler = SigAction::new(
+        SigHandler::SigAction(on_sigsys),
+        SaFlags::SA_SIGINFO,
+        SigSet::empty(),
+    );
+    // SAFETY: the handler only makes async-signal-safe calls
+    unsafe { sigaction(Signal::SIGSYS, &handler) }.map_err(|e| {
+        ProcessFunError::ProcessError(format!("Failed to install SIGSYS handler: {}", e))
+    })?;
+
+    // Needed to install a filter without privileges
+    prctl::set_no_new_privs()
+        .map_err(|e| ProcessFunError::ProcessError(format!("Failed to set no_new_privs: {}", e)))?;
+
+    let fprog = sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    // SAFETY: `fprog` points to a valid program that outlives the call
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const sock_fprog,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        -1 => Err(ProcessFunError::ProcessError(format!(
+            "Failed to install seccomp filter: {}",
+            std::io::Error::last_os_error()
+        ))),
+        thread => Err(ProcessFunError::ProcessError(format!(
+            "Failed to install seccomp filter: thread {} could not be synchronized",
+            thread
+        ))),
+    }
+}
+
+/// Compile `rules` into a BPF program that allows matching system calls and traps on all
+/// others
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn compile(rules: &[Rule]) -> Result<Vec<sock_filter>, ProcessFunError> {
+    use nix::libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let load = |offset| bpf_stmt(BPF_LD | BPF_W | BPF_ABS, offset);
+    let allow = bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW);
+
+    let mut program = vec![
+        // System calls of other architectures have different numbers
+        load(ARCH_OFFSET),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+        bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        load(NR_OFFSET),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        bpf_jump(libc::BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+        bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+    ]);
+
+    for rule in rules {
+        let nr = rule.nr as u32;
+        match rule.arg {
+            None => program.extend([bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, 1), allow]),
+            // Compare the lower half of the argument, and reload the number if it differs
+            Some((index, value)) => program.extend([
+                bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, 3),
+                load(ARGS_OFFSET + index * 8),
+                bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, value as u32, 0, 1),
+                allow,
+                load(NR_OFFSET),
+            ]),
+        }
+    }
+    program.push(bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_TRAP));
+
+    if program.len() > u16::MAX as usize {
+        return Err(ProcessFunError::ProcessError(
+            "Seccomp filter allows too many system calls".to_string(),
+        ));
+    }
+    Ok(program)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn compile(_rules: &[Rule]) -> Result<Vec<sock_filter>, ProcessFunError> {
+    Err(ProcessFunError::ProcessError(
+        "Seccomp filters are not supported on this architecture".to_string(),
+    ))
+}
+
+fn bpf_stmt(code: u32, k: u32) -> sock_filter {
+    bpf_jump(code, k, 0, 0)
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// The fields of `siginfo_t` for `SIGSYS`, which the libc crate does not expose
+#[repr(C)]
+struct SigsysInfo {
+    signo: c_int,
+    errno: c_int,
+    code: c_int,
+    call_addr: *mut c_void,
+    syscall: c_int,
+    arch: c_uint,
+}
+
+/// Report the forbidden system call to the parent and exit, using async-signal-safe calls only
+extern "C" fn on_sigsys(_: c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    // SAFETY: the kernel passes the `SIGSYS` variant of `siginfo_t` for seccomp traps
+    let syscall = unsafe { (*info.cast::<SigsysInfo>()).syscall };
+    let frame = wire::violation_frame(syscall);
+    let pipe = VIOLATION_PIPE.load(Ordering::SeqCst);
+    // SAFETY: `write` and `_exit` are async-signal-safe, and writes of less than `PIPE_BUF`
+    // bytes to a pipe are atomic
+    unsafe {
+        libc::write(pipe, frame.as_ptr().cast(), frame.len());
+        libc::_exit(VIOLATION_EXIT_CODE);
+    }
+}
+
+/// The number of the system call `name` on the current architecture
+fn syscall_number(name: &str) -> Option<c_long> {
+    SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS)
+        .find(|(entry, _)| entry.strip_prefix("SYS_") == Some(name))
+        .map(|&(_, nr)| nr)
+}
+
+/// The name of the system call `nr` on the current architecture, or its number if it is not
+/// known
+pub(crate) fn syscall_name(nr: i32) -> String {
+    SYSCALLS
+        .iter()
+        .chain(ARCH_SYSCALLS)
+        .find(|&&(_, entry)| entry == c_long::from(nr))
+        .and_then(|(name, _)| name.strip_prefix("SYS_"))
+        .map_or_else(|| nr.to_string(), str::to_string)
+}
+
+macro_rules! syscalls {
+    ($($name:ident)*) => {
+        &[$((stringify!($name), libc::$name)),*]
+    };
+}
+
+/// System calls that can be allowed by name
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+static SYSCALLS: &[(&str, c_long)] = syscalls!(
+    SYS_read SYS_write SYS_close SYS_fstat SYS_lseek SYS_mmap SYS_mprotect SYS_munmap
+    SYS_brk SYS_rt_sigaction SYS_rt_sigprocmask SYS_rt_sigreturn SYS_ioctl SYS_pread64
+    SYS_pwrite64 SYS_readv SYS_writev SYS_sched_yield SYS_mremap SYS_msync SYS_mincore
+    SYS_madvise SYS_shmget SYS_shmat SYS_shmctl SYS_dup SYS_nanosleep SYS_getitimer
+    SYS_setitimer SYS_getpid SYS_sendfile SYS_socket SYS_connect SYS_accept SYS_sendto
+    SYS_recvfrom SYS_sendmsg SYS_recvmsg SYS_shutdown SYS_bind SYS_listen SYS_getsockname
+    SYS_getpeername SYS_socketpair SYS_setsockopt SYS_getsockopt SYS_clone SYS_execve
+    SYS_exit SYS_wait4 SYS_kill SYS_uname SYS_semget SYS_semop SYS_semctl SYS_shmdt
+    SYS_msgget SYS_msgsnd SYS_msgrcv SYS_msgctl SYS_fcntl SYS_flock SYS_fsync SYS_fdatasync
+    SYS_truncate SYS_ftruncate SYS_getcwd SYS_chdir SYS_fchdir SYS_fchmod SYS_fchown
+    SYS_umask SYS_gettimeofday SYS_getrusage SYS_sysinfo SYS_times SYS_ptrace SYS_getuid
+    SYS_syslog SYS_getgid SYS_setuid SYS_setgid SYS_geteuid SYS_getegid SYS_setpgid
+    SYS_getppid SYS_setsid SYS_setreuid SYS_setregid SYS_getgroups SYS_setgroups
+    SYS_setresuid SYS_getresuid SYS_setresgid SYS_getresgid SYS_getpgid SYS_setfsuid
+    SYS_setfsgid SYS_getsid SYS_capget SYS_capset SYS_rt_sigpending SYS_rt_sigtimedwait
+    SYS_rt_sigqueueinfo SYS_rt_sigsuspend SYS_sigaltstack SYS_personality SYS_statfs
+    SYS_fstatfs SYS_getpriority SYS_setpriority SYS_sched_setparam SYS_sched_getparam
+    SYS_sched_setscheduler SYS_sched_getscheduler SYS_sched_get_priority_max
+    SYS_sched_get_priority_min SYS_sched_rr_get_interval SYS_mlock SYS_munlock SYS_mlockall
+    SYS_munlockall SYS_vhangup SYS_pivot_root SYS_prctl SYS_adjtimex SYS_chroot SYS_sync
+    SYS_acct SYS_settimeofday SYS_mount SYS_umount2 SYS_swapon SYS_swapoff SYS_reboot
+    SYS_sethostname SYS_setdomainname SYS_init_module SYS_delete_module SYS_quotactl
+    SYS_nfsservctl SYS_gettid SYS_readahead SYS_setxattr SYS_lsetxattr SYS_fsetxattr
+    SYS_getxattr SYS_lgetxattr SYS_fgetxattr SYS_listxattr SYS_llistxattr SYS_flistxattr
+    SYS_removexattr SYS_lremovexattr SYS_fremovexattr SYS_tkill SYS_futex
+    SYS_sched_setaffinity SYS_sched_getaffinity SYS_io_setup SYS_io_destroy SYS_io_getevents
+    SYS_io_submit SYS_io_cancel SYS_lookup_dcookie SYS_remap_file_pages SYS_getdents64
+    SYS_set_tid_address SYS_restart_syscall SYS_semtimedop SYS_fadvise64 SYS_timer_create
+    SYS_timer_settime SYS_timer_gettime SYS_timer_getoverrun SYS_timer_delete
+    SYS_clock_settime SYS_clock_gettime SYS_clock_getres SYS_clock_nanosleep SYS_exit_group
+    SYS_epoll_ctl SYS_tgkill SYS_mbind SYS_set_mempolicy SYS_get_mempolicy SYS_mq_open
+    SYS_mq_unlink SYS_mq_timedsend SYS_mq_timedreceive SYS_mq_notify SYS_mq_getsetattr
+    SYS_kexec_load SYS_waitid SYS_add_key SYS_request_key SYS_keyctl SYS_ioprio_set
+    SYS_ioprio_get SYS_inotify_add_watch SYS_inotify_rm_watch SYS_migrate_pages SYS_openat
+    SYS_mkdirat SYS_mknodat SYS_fchownat SYS_newfstatat SYS_unlinkat SYS_linkat
+    SYS_symlinkat SYS_readlinkat SYS_fchmodat SYS_faccessat SYS_pselect6 SYS_ppoll
+    SYS_unshare SYS_set_robust_list SYS_get_robust_list SYS_splice SYS_tee SYS_vmsplice
+    SYS_move_pages SYS_utimensat SYS_epoll_pwait SYS_timerfd_create SYS_fallocate
+    SYS_timerfd_settime SYS_timerfd_gettime SYS_accept4 SYS_signalfd4 SYS_eventfd2
+    SYS_epoll_create1 SYS_dup3 SYS_pipe2 SYS_inotify_init1 SYS_preadv SYS_pwritev
+    SYS_rt_tgsigqueueinfo SYS_perf_event_open SYS_recvmmsg SYS_fanotify_init
+    SYS_fanotify_mark SYS_prlimit64 SYS_name_to_handle_at SYS_open_by_handle_at
+    SYS_clock_adjtime SYS_syncfs SYS_sendmmsg SYS_setns SYS_getcpu SYS_process_vm_readv
+    SYS_process_vm_writev SYS_kcmp SYS_finit_module SYS_sched_setattr SYS_sched_getattr
+    SYS_renameat2 SYS_seccomp SYS_getrandom SYS_memfd_create SYS_bpf SYS_execveat
+    SYS_userfaultfd SYS_membarrier SYS_mlock2 SYS_copy_file_range SYS_preadv2 SYS_pwritev2
+    SYS_pkey_mprotect SYS_pkey_alloc SYS_pkey_free SYS_statx SYS_rseq SYS_pidfd_send_signal
+    SYS_io_uring_setup SYS_io_uring_enter SYS_io_uring_register SYS_open_tree SYS_move_mount
+    SYS_fsopen SYS_fsconfig SYS_fsmount SYS_fspick SYS_pidfd_open SYS_clone3 SYS_close_range
+    SYS_openat2 SYS_pidfd_getfd SYS_faccessat2 SYS_process_madvise SYS_epoll_pwait2
+    SYS_mount_setattr SYS_quotactl_fd SYS_landlock_create_ruleset SYS_landlock_add_rule
+    SYS_landlock_restrict_self SYS_memfd_secret SYS_process_mrelease SYS_futex_waitv
+    SYS_set_mempolicy_home_node SYS_mseal
+);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+static SYSCALLS: &[(&str, c_long)] = &[];
+
+/// System calls that only exist on some architectures
+#[cfg(target_arch = "x86_64")]
+static ARCH_SYSCALLS: &[(&str, c_long)] = syscalls!(
+    SYS_open SYS_stat SYS_lstat SYS_poll SYS_access SYS_pipe SYS_select SYS_dup2 SYS_pause
+    SYS_alarm SYS_fork SYS_vfork SYS_getdents SYS_rename SYS_mkdir SYS_rmdir SYS_creat
+    SYS_link SYS_unlink SYS_symlink SYS_readlink SYS_chmod SYS_chown SYS_lchown
+    SYS_getrlimit SYS_getpgrp SYS_utime SYS_mknod SYS_uselib SYS_ustat SYS_sysfs
+    SYS_modify_ldt SYS__sysctl SYS_arch_prctl SYS_setrlimit SYS_iopl SYS_ioperm SYS_getpmsg
+    SYS_putpmsg SYS_afs_syscall SYS_tuxcall SYS_security SYS_time SYS_set_thread_area
+    SYS_get_thread_area SYS_epoll_create SYS_epoll_ctl_old SYS_epoll_wait_old SYS_epoll_wait
+    SYS_utimes SYS_vserver SYS_inotify_init SYS_futimesat SYS_renameat SYS_sync_file_range
+    SYS_signalfd SYS_eventfd SYS_kexec_file_load SYS_fchmodat2
+);
+#[cfg(not(target_arch = "x86_64"))]
+static ARCH_SYSCALLS: &[(&str, c_long)] = &[];
@@ -0,0 +1,297 @@
+//! Transfer of large results through shared memory instead of the pipe.
+//!
+//! The parent creates a sealable memfd before the fork, which the child inherits. The child
+//! serializes its result into a buffer until it reaches the threshold, and from then on directly
+//! into the memfd, so a large result is never held in memory twice. It then seals the memfd
+//! against writing and resizing, and only sends the length of the result in a `SharedResult`
+//! frame. The parent checks the seals, so neither the child nor anything it spawned can change
+//! the result any more, maps the memfd and deserializes the result directly from the mapping.
+//! Smaller results are sent through the pipe as usual, and so are larger ones the child cannot
+//! write into the memfd, e.g. because the file size limit is too low, serializing them again.
+
+use crate::wire::{Frame, FrameKind, FrameWriter};
+use crate::{ProcessFunError, ProcessOptions};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::stat::fstat;
+use nix::unistd::write;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::ffi::c_void;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::ptr::NonNull;
+use std::sync::{Mutex, PoisonError};
+
+/// Size of a serialized result from which shared memory is used unless another threshold is
+/// given
+pub const DEFAULT_SHARED_MEMORY_THRESHOLD: usize = 1 << 20;
+
+/// Seals the child must add before the parent maps the memfd
+const REQUIRED_SEALS: SealFlag = SealFlag::F_SEAL_WRITE
+    .union(SealFlag::F_SEAL_SHRINK)
+    .union(SealFlag::F_SEAL_GROW);
+
+/// Amount of serialized data collected before it is written into the memfd at once, once the
+/// result has reached the threshold
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Size of the payload of a `SharedResult` frame
+const LENGTH_SIZE: usize = std::mem::size_of::<u64>();
+
+/// The shared memory of the running process function, set in the child by `use_in_child`
+static CHILD_MEMORY: Mutex<Option<SharedMemory>> = Mutex::new(None);
+
+/// The memfd large results of a call are transferred through, created before the fork
+#[derive(Debug, Default)]
+pub struct SharedMemory {
+    memfd: Option<OwnedFd>,
+    threshold: usize,
+    /// The file size limit of the child, which also applies to the memfd
+    max_size: Option<u64>,
+}
+
+impl SharedMemory {
+    /// Create the memfd if `options` enable shared memory
+    pub fn new(options: &ProcessOptions) -> Result<Self, ProcessFunError> {
+        let Some(threshold) = options.shared_memory else {
+            return Ok(Self::default());
+        };
+        let memfd = memfd_create(
+            c"process-fun-result",
+            MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+        )
+        .map_err(|e| {
+            ProcessFunError::ProcessError(format!("Failed to create shared memory: {}", e))
+        })?;
+        Ok(Self {
+            memfd: Some(memfd),
+            threshold,
+            max_size: options.limits.file_size,
+        })
+    }
+
+    /// Use the memfd for the result of the process function running in this child. Must be
+    /// called in the child, replacing the memory of an outer process function.
+    pub fn use_in_child(self) {
+        *CHILD_MEMORY.lock().unwrap_or_else(PoisonError::into_inner) = Some(self);
+    }
+
+    /// Deserialize the result announced by a `SharedResult` frame from the memfd
+    pub(crate) fn decode<T: DeserializeOwned>(&self, frame: &Frame) -> Result<T, ProcessFunError> {
+        let memfd = self.memfd.as_ref().ok_or_else(|| {
+            protocol_error("SharedResult frame without shared memory".to_string())
+        })?;
+        let bytes: [u8; LENGTH_SIZE] = frame.payload.as_slice().try_into().map_err(|_| {
+            protocol_error(format!(
+                "shared result frame has {} bytes instead of {}",
+                frame.payload.len(),
+                LENGTH_SIZE
+            ))
+        })?;
+        let len = u64::from_le_bytes(bytes);
+
+        let seals = fcntl(memfd.as_raw_fd(), FcntlArg::F_GET_SEALS)
+            .map(SealFlag::from_bits_truncate)
+            .map_err(|e| shared_memory_error("read the seals of", e))?;
+        if !seals.contains(REQUIRED_SEALS) {
+            return Err(protocol_error(
+                "shared result was not sealed by the child".to_string(),
+            ));
+        }
+        let size = fstat(memfd.as_raw_fd())
+            .map_err(|e| shared_memory_error("read the size of", e))?
+            .st_size;
+        if size as u64 != len {
+            return Err(protocol_error(format!(
+                "shared result has {} bytes instead of {}",
+                size, len
+            )));
+        }
+
+        let len = usize::try_from(len)
+            .map_err(|_| protocol_error(format!("shared result of {} bytes is too large", len)))?;
+        match NonZeroUsize::new(len) {
+            Some(len) => frame.codec.decode(&Mapping::new(memfd, len)?),
+            None => frame.codec.decode(&[]),
+        }
+    }
+}
+
+/// The memfd the result of the process function running in this child may be written to
+pub(crate) fn child_memfd() -> Option<RawFd> {
+    CHILD_MEMORY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .and_then(|memory| memory.memfd.as_ref())
+        .map(AsRawFd::as_raw_fd)
+}
+
+/// Write the final frame for the outcome of the call, putting a large result into the shared
+/// memory of the child if it has any
+pub(crate) fn write_outcome<T: Serialize, W: Write>(
+    writer: &mut FrameWriter<W>,
+    outcome: &Result<T, ProcessFunError>,
+) -> Result<(), ProcessFunError> {
+    let memory = CHILD_MEMORY
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    let (Ok(value), Some(memory)) = (outcome, memory) else {
+        return writer.write_outcome(outcome);
+    };
+    let Some(memfd) = &memory.memfd else {
+        return writer.write_outcome(outcome);
+    };
+
+    let mut result = ResultWriter {
+        memfd,
+        threshold: memory.threshold,
+        max_size: memory.max_size,
+        buffer: vec![],
+        stored: 0,
+        failed: false,
+    };
+    // The codec also fails if the memfd could not be written, which is handled below
+    if let Err(e) = writer.codec().encode_into(value, &mut result) {
+        if !result.failed {
+            return Err(e);
+        }
+    }
+    match result.finish() {
+        Ok(Some(len)) => writer.write_frame(FrameKind::SharedResult, &len.to_le_bytes()),
+        Ok(None) => writer.write_frame(FrameKind::Result, &result.buffer),
+        // What was written into the memfd is discarded along with it by the parent
+        Err(_) => writer.write_outcome(outcome),
+    }
+}
+
+/// Serializes a result into memory until it reaches the threshold, and from then on into the
+/// memfd
+struct ResultWriter<'a> {
+    memfd: &'a OwnedFd,
+    threshold: usize,
+    max_size: Option<u64>,
+    buffer: Vec<u8>,
+    /// Bytes already written into the memfd
+    stored: u64,
+    /// Set if the memfd could not be written, as opposed to the codec failing
+    failed: bool,
+}
+
+impl ResultWriter<'_> {
+    fn len(&self) -> u64 {
+        self.stored + self.buffer.len() as u64
+    }
+
+    /// Write the buffer into the memfd
+    fn store(&mut self) -> io::Result<()> {
+        // Exceeding the file size limit would kill the child with `SIGXFSZ`
+        if self.max_size.is_some_and(|max_size| self.len() > max_size) {
+            self.failed = true;
+            return Err(io::Error::other("result exceeds the file size limit"));
+        }
+
+        let mut written = 0;
+        while written < self.buffer.len() {
+            match write(self.memfd.as_fd(), &self.buffer[written..]) {
+                Ok(n) => written += n,
+                Err(Errno::EINTR) => {}
+                Err(e) => {
+                    self.failed = true;
+                    return Err(e.into());
+                }
+            }
+        }
+        self.stored += written as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Store the rest of a result that reached the threshold and seal the memfd, returning the
+    /// length of the result. A smaller result is left in the buffer.
+    fn finish(&mut self) -> io::Result<Option<u64>> {
+        if self.failed {
+            return Err(io::Error::other(
+                "failed to write the result into shared memory",
+            ));
+        }
+        if self.len() < self.threshold as u64 {
+            return Ok(None);
+        }
+        self.store()?;
+        fcntl(
+            self.memfd.as_raw_fd(),
+            FcntlArg::F_ADD_SEALS(REQUIRED_SEALS | SealFlag::F_SEAL_SEAL),
+        )?;
+        Ok(Some(self.stored))
+    }
+}
+
+impl Write for ResultWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        // Below the threshold the result may still go through the pipe
+        if self.buffer.len() >= WRITE_BUFFER_SIZE && self.len() >= self.threshold as u64 {
+            self.store()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A read-only mapping of the memfd
+struct Mapping {
+    ptr: NonNull<c_void>,
+    len: NonZeroUsize,
+}
+
+impl Mapping {
+    fn new(memfd: &OwnedFd, len: NonZeroUsize) -> Result<Self, ProcessFunError> {
+        // SAFETY: a new mapping does not alias any memory, and the seals keep the file from
+        // being written or truncated while it is mapped
+        let ptr = unsafe {
+            mmap(
+                None,
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                memfd,
+                0,
+            )
+        }
+        .map_err(|e| shared_memory_error("map", e))?;
+        Ok(Self { ptr, len })
+    }
+}
+
+impl std::ops::Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is readable and `len` bytes long until it is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().cast(), self.len.get()) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is no longer borrowed
+        let _ = unsafe { munmap(self.ptr, self.len.get()) };
+    }
+}
+
+fn protocol_error(message: String) -> ProcessFunError {
+    ProcessFunError::ProtocolError(message)
+}
+
+fn shared_memory_error(action: &str, e: Errno) -> ProcessFunError {
+    ProcessFunError::ProcessError(format!("Failed to {} shared memory: {}", action, e))
+}
@@ -0,0 +1,132 @@
+//! Streaming process functions, which send each item of the iterator they return to the
+//! parent as soon as it is produced instead of collecting them into a single result.
+
+use crate::{ProcessFunError, ProcessOptions, ProcessWrapper, Termination};
+use interprocess::unnamed_pipe::Recver;
+use nix::sys::wait::WaitStatus;
+use nix::unistd::Pid;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Items produced by a streaming process function, in the order the child produced them.
+///
+/// The stream ends with an error if the child failed, e.g. panicked, after producing some
+/// items. Dropping the stream terminates the child like dropping a `ProcessWrapper`.
+#[derive(Debug)]
+pub struct ProcessStream<T> {
+    process: ProcessWrapper<()>,
+    _ghost: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Display for ProcessStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProcessStream(pid={})", self.process.pid())
+    }
+}
+
+impl<T> ProcessStream<T> {
+    /// Create a new ProcessStream
+    pub fn new(child_pid: Pid, receiver: Recver) -> Self {
+        Self::with_options(child_pid, receiver, &ProcessOptions::default())
+    }
+
+    /// Create a new ProcessStream for a child started with `options`
+    pub fn with_options(child_pid: Pid, receiver: Recver, options: &ProcessOptions) -> Self {
+        Self {
+            process: ProcessWrapper::with_options(child_pid, receiver, options),
+            _ghost: PhantomData,
+        }
+    }
+
+    /// Capture the streams of the child that `output` pipes to the parent
+    pub fn with_output(mut self, output: crate::OutputPipes) -> Result<Self, ProcessFunError> {
+        self.process = self.process.with_output(output)?;
+        Ok(self)
+    }
+
+    /// Receive the result through `shared_memory`, see `ProcessWrapper::with_shared_memory`
+    pub fn with_shared_memory(mut self, shared_memory: crate::SharedMemory) -> Self {
+        self.process = self.process.with_shared_memory(shared_memory);
+        self
+    }
+
+    /// Progress updates the child sent since they were last taken, see
+    /// `ProcessWrapper::progress`
+    pub fn progress<P: serde::de::DeserializeOwned>(
+        &mut self,
+    ) -> impl Iterator<Item = Result<P, ProcessFunError>> + '_ {
+        self.process.progress()
+    }
+
+    /// The process id of the child
+    pub fn pid(&self) -> Pid {
+        self.process.pid()
+    }
+
+    /// The exit status of the child, available once the stream has ended or was aborted
+    pub fn status(&self) -> Option<WaitStatus> {
+        self.process.status()
+    }
+
+    /// How much the child used, available once the stream has ended or was aborted
+    pub fn report(&self) -> Option<&crate::ProcessReport> {
+        self.process.report()
+    }
+
+    /// The stdout and stderr captured from the child, available once the stream has ended or
+    /// was aborted
+    pub fn take_output(&mut self) -> Option<(crate::CapturedOutput, crate::CapturedOutput)> {
+        self.process.take_output()
+    }
+
+    /// The accounting of the child's cgroup, available once the stream has ended
+    #[cfg(feature = "cgroup")]
+    pub fn cgroup_stats(&self) -> Option<&crate::CgroupStats> {
+        self.process.cgroup_stats()
+    }
+
+    /// Abort the process according to its termination policy and report how it ended.
+    /// Items that were received but not yet taken are discarded.
+    pub fn abort(&mut self) -> Result<Termination, ProcessFunError> {
+        self.process.items.clear();
+        self.process.abort()
+    }
+}
+
+impl<T> Iterator for ProcessStream<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    type Item = Result<T, ProcessFunError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(payload) = self.process.items.pop_front() {
+                return Some(self.process.codec.decode(&payload));
+            }
+            if self.process.finished {
+                return None;
+            }
+
+            // Block until the child sends more items or closes the pipe. An error is only
+            // reported once the items received before it have been taken.
+            if self.process.receiver.is_some() {
+                let process = &mut self.process;
+                if let Err(e) = process
+                    .ensure_start_time()
+                    .and_then(|_| process.read_chunk())
+                {
+                    process.read_error.get_or_insert(e);
+                }
+                continue;
+            }
+
+            let result = self
+                .process
+                .reap()
+                .and_then(|status| self.process.finish(Ok(()), status));
+            self.process.finished = true;
+            return result.err().map(Err);
+        }
+    }
+}
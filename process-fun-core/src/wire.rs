@@ -0,0 +1,489 @@
+//! Wire protocol between a child process and its parent.
+//!
+//! Everything the child sends travels over a single pipe as a header followed by
+//! length-prefixed frames:
+//!
+//! ```text
+//! stream := header frame*
+//! header := magic "PFUN" (4 bytes) | version (u16 LE) | flags (u16 LE)
+//! frame  := tag (u8) | length (u32 LE) | payload (length bytes)
+//! ```
+//!
+//! | Tag | Frame          | Payload                                                       |
+//! |-----|----------------|---------------------------------------------------------------|
+//! | 1   | `Start`        | start time of the child in clock ticks (u64 LE)               |
+//! | 2   | `Result`       | the serialized return value                                   |
+//! | 3   | `Error`        | a serialized `ProcessFunError` that prevented the call        |
+//! | 4   | `Panic`        | a serialized `ProcessFunError::ChildPanicked`                 |
+//! | 5   | `Log`          | UTF-8 text                                                    |
+//! | 6   | `Progress`     | a serialized progress update                                  |
+//! | 7   | `Item`         | a serialized item of a streaming process function             |
+//! | 8   | `Message`      | a serialized message sent over a `ProcessChannel`             |
+//! | 9   | `Violation`    | number of a system call forbidden by seccomp (i32 LE)         |
+//! | 10  | `SharedResult` | size of the serialized return value in shared memory (u64 LE) |
+//!
+//! The low byte of the flags is the id of the `CodecKind` the payloads are serialized with,
+//! zero for bincode. The other bits are currently zero.
+//!
+//! The child sends `Start` first, once it is set up. `Result`, `SharedResult`, `Error`, `Panic` and
+//! `Violation` are final: exactly one of them ends the stream, and no frame may follow it.
+//! Streaming process functions send their items as `Item` frames and end with a `Result` carrying
+//! `()`. Each direction of a `ProcessChannel` is a separate stream of `Message` frames without
+//! `Start`. A stream that violates this, or ends in the middle of the header or a frame, is
+//! reported as `ProcessFunError::ProtocolError` describing what was wrong.
+
+use crate::codec::{self, CodecKind};
+use crate::{pid_start_time, seccomp, ProcessFunError};
+use nix::unistd::getpid;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::mem;
+
+/// Magic number starting every stream
+pub const MAGIC: [u8; 4] = *b"PFUN";
+
+/// Version of the protocol, increased on incompatible changes
+pub const VERSION: u16 = 1;
+
+/// Size of the stream header
+pub const HEADER_SIZE: usize = 8;
+
+/// Size of the tag and length preceding the payload of a frame
+pub const FRAME_HEADER_SIZE: usize = 5;
+
+/// Size of the payload of a `Start` frame
+const START_TIME_SIZE: usize = mem::size_of::<u64>();
+
+/// Size of the payload of a `Violation` frame
+const SYSCALL_SIZE: usize = mem::size_of::<i32>();
+
+/// Type of a frame, sent as its tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Start = 1,
+    Result = 2,
+    Error = 3,
+    Panic = 4,
+    Log = 5,
+    Progress = 6,
+    Item = 7,
+    Message = 8,
+    Violation = 9,
+    SharedResult = 10,
+}
+
+impl FrameKind {
+    /// The frame kind sent with `tag`, if it is known
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Self::Start),
+            2 => Some(Self::Result),
+            3 => Some(Self::Error),
+            4 => Some(Self::Panic),
+            5 => Some(Self::Log),
+            6 => Some(Self::Progress),
+            7 => Some(Self::Item),
+            8 => Some(Self::Message),
+            9 => Some(Self::Violation),
+            10 => Some(Self::SharedResult),
+            _ => None,
+        }
+    }
+
+    /// Whether the frame ends the stream
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            Self::Result | Self::SharedResult | Self::Error | Self::Panic | Self::Violation
+        )
+    }
+}
+
+/// A frame received from the child
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+    /// The codec of the stream the frame was received on
+    pub codec: CodecKind,
+}
+
+impl Frame {
+    /// The start time carried by a `Start` frame
+    pub fn start_time(&self) -> Result<u64, ProcessFunError> {
+        let bytes: [u8; START_TIME_SIZE] = self.payload.as_slice().try_into().map_err(|_| {
+            protocol_error(format!(
+                "start frame has {} bytes instead of {}",
+                self.payload.len(),
+                START_TIME_SIZE
+            ))
+        })?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Decode the outcome of the call carried by a final frame
+    pub fn into_outcome<T: serde::de::DeserializeOwned>(self) -> Result<T, ProcessFunError> {
+        match self.kind {
+            FrameKind::Result => self.codec.decode(&self.payload),
+            FrameKind::Error | FrameKind::Panic => {
+                Err(self.codec.decode::<ProcessFunError>(&self.payload)?)
+            }
+            FrameKind::Violation => {
+                let bytes: [u8; SYSCALL_SIZE] =
+                    self.payload.as_slice().try_into().map_err(|_| {
+                        protocol_error(format!(
+                            "violation frame has {} bytes instead of {}",
+                            self.payload.len(),
+                            SYSCALL_SIZE
+                        ))
+                    })?;
+                Err(ProcessFunError::SandboxViolation {
+                    syscall: seccomp::syscall_name(i32::from_le_bytes(bytes)),
+                })
+            }
+            FrameKind::SharedResult => Err(protocol_error(
+                "SharedResult frame without shared memory".to_string(),
+            )),
+            kind => Err(protocol_error(format!("{:?} frame is not final", kind))),
+        }
+    }
+}
+
+#[inline]
+fn protocol_error(message: String) -> ProcessFunError {
+    ProcessFunError::ProtocolError(message)
+}
+
+/// The header starting every stream whose payloads are serialized with `codec`
+pub fn stream_header(codec: CodecKind) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    header[6..].copy_from_slice(&u16::from(codec.id()).to_le_bytes());
+    header
+}
+
+/// The tag and length preceding a payload of `len` bytes
+pub fn frame_header(
+    kind: FrameKind,
+    len: usize,
+) -> Result<[u8; FRAME_HEADER_SIZE], ProcessFunError> {
+    let len = u32::try_from(len).map_err(|_| {
+        protocol_error(format!(
+            "{:?} frame of {} bytes exceeds the maximal frame size",
+            kind, len
+        ))
+    })?;
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    header[0] = kind as u8;
+    header[1..].copy_from_slice(&len.to_le_bytes());
+    Ok(header)
+}
+
+/// A complete `Violation` frame, built without allocating so that it can be sent from a
+/// signal handler
+pub fn violation_frame(syscall: i32) -> [u8; FRAME_HEADER_SIZE + SYSCALL_SIZE] {
+    let mut frame = [0u8; FRAME_HEADER_SIZE + SYSCALL_SIZE];
+    frame[0] = FrameKind::Violation as u8;
+    frame[1..FRAME_HEADER_SIZE].copy_from_slice(&(SYSCALL_SIZE as u32).to_le_bytes());
+    frame[FRAME_HEADER_SIZE..].copy_from_slice(&syscall.to_le_bytes());
+    frame
+}
+
+/// Writes the stream of a child process
+#[derive(Debug)]
+pub struct FrameWriter<W: Write> {
+    writer: W,
+    codec: CodecKind,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Write the stream header for the default codec
+    pub fn new(writer: W) -> Result<Self, ProcessFunError> {
+        Self::with_codec(writer, codec::default_codec())
+    }
+
+    /// Write the stream header for payloads serialized with `codec`
+    pub fn with_codec(mut writer: W, codec: CodecKind) -> Result<Self, ProcessFunError> {
+        writer.write_all(&stream_header(codec))?;
+        Ok(Self { writer, codec })
+    }
+
+    /// Write the stream header for the default codec and the `Start` frame of the calling
+    /// process
+    pub fn start(writer: W) -> Result<Self, ProcessFunError> {
+        Self::start_with_codec(writer, codec::default_codec())
+    }
+
+    /// Write the stream header for `codec` and the `Start` frame of the calling process
+    pub fn start_with_codec(writer: W, codec: CodecKind) -> Result<Self, ProcessFunError> {
+        let mut this = Self::with_codec(writer, codec)?;
+        let start_time = pid_start_time(getpid())?;
+        this.write_frame(FrameKind::Start, &start_time.to_le_bytes())?;
+        Ok(this)
+    }
+
+    /// Write a single frame
+    pub fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> Result<(), ProcessFunError> {
+        self.writer.write_all(&frame_header(kind, payload.len())?)?;
+        self.writer.write_all(payload)?;
+        Ok(())
+    }
+
+    /// Write the final frame for the outcome of the call. Nothing is written if the value
+    /// cannot be serialized.
+    pub fn write_outcome<T: Serialize>(
+        &mut self,
+        outcome: &Result<T, ProcessFunError>,
+    ) -> Result<(), ProcessFunError> {
+        match outcome {
+            Ok(value) => self.write_frame(FrameKind::Result, &self.codec.encode(value)?),
+            Err(error @ ProcessFunError::ChildPanicked { .. }) => {
+                self.write_frame(FrameKind::Panic, &self.codec.encode(error)?)
+            }
+            Err(error) => self.write_frame(FrameKind::Error, &self.codec.encode(error)?),
+        }
+    }
+
+    /// Write an `Item` frame
+    pub fn write_item<T: Serialize>(&mut self, item: &T) -> Result<(), ProcessFunError> {
+        self.write_frame(FrameKind::Item, &self.codec.encode(item)?)
+    }
+
+    /// Write a `Progress` frame
+    pub fn write_progress<P: Serialize>(&mut self, value: &P) -> Result<(), ProcessFunError> {
+        self.write_frame(FrameKind::Progress, &self.codec.encode(value)?)
+    }
+
+    /// Write a `Log` frame
+    pub fn write_log(&mut self, message: &str) -> Result<(), ProcessFunError> {
+        self.write_frame(FrameKind::Log, message.as_bytes())
+    }
+
+    /// The codec the payloads are serialized with
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Splits the stream received from a child process into frames, checking their order
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Number of bytes at the start of the buffer that have been decoded, which are only
+    /// removed before more bytes are added
+    offset: usize,
+    header_read: bool,
+    /// Kind and payload length of the frame whose header has been decoded, which is followed
+    /// by the payload
+    pending: Option<(FrameKind, usize)>,
+    started: bool,
+    ended: bool,
+    /// The codec the stream must use, if any
+    expected_codec: Option<CodecKind>,
+    /// The codec recorded in the header
+    codec: CodecKind,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a decoder for a stream that does not start with a `Start` frame, such as a
+    /// direction of a `ProcessChannel`
+    pub fn without_start() -> Self {
+        Self {
+            started: true,
+            ..Self::default()
+        }
+    }
+
+    /// Reject streams whose payloads are not serialized with `codec`
+    pub fn expect_codec(mut self, codec: CodecKind) -> Self {
+        self.expected_codec = Some(codec);
+        self
+    }
+
+    /// The codec recorded in the header, bincode until the header has been read
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    /// Perform a single read of at most `max` bytes from `reader` into the decoder
+    pub fn read_from(&mut self, reader: &mut impl Read, max: usize) -> io::Result<usize> {
+        self.compact();
+        let len = self.buffer.len();
+        self.buffer.resize(len + max, 0);
+        let result = reader.read(&mut self.buffer[len..]);
+        self.buffer.truncate(len + *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    /// Add received bytes to the decoder
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.compact();
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Remove the decoded bytes from the buffer, once for all frames taken since the last read
+    fn compact(&mut self) {
+        self.buffer.drain(..self.offset);
+        self.offset = 0;
+    }
+
+    /// The bytes that have not been decoded yet
+    fn unread(&self) -> &[u8] {
+        &self.buffer[self.offset..]
+    }
+
+    /// The next complete frame, or `None` if more bytes are needed
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, ProcessFunError> {
+        if !self.header_read {
+            if self.unread().len() < HEADER_SIZE {
+                return Ok(None);
+            }
+            self.read_header()?;
+        }
+        let (kind, len) = match self.pending {
+            Some(pending) => pending,
+            None if self.unread().len() < FRAME_HEADER_SIZE => return Ok(None),
+            None => {
+                let pending = self.read_frame_header()?;
+                self.pending = Some(pending);
+                pending
+            }
+        };
+        if self.unread().len() < len {
+            return Ok(None);
+        }
+
+        // A payload filling the buffer, such as a large result, is handed out without copying
+        // it
+        self.pending = None;
+        let payload = if self.offset == 0 && self.buffer.len() == len {
+            mem::take(&mut self.buffer)
+        } else {
+            let payload = self.unread()[..len].to_vec();
+            self.offset += len;
+            payload
+        };
+        if self.offset == self.buffer.len() {
+            self.buffer.clear();
+            self.offset = 0;
+        }
+
+        self.started = true;
+        self.ended = kind.is_final();
+        Ok(Some(Frame {
+            kind,
+            payload,
+            codec: self.codec,
+        }))
+    }
+
+    /// Check the header of the next frame and skip it
+    fn read_frame_header(&mut self) -> Result<(FrameKind, usize), ProcessFunError> {
+        let tag = self.unread()[0];
+        let kind = FrameKind::from_tag(tag)
+            .ok_or_else(|| protocol_error(format!("unknown frame tag {}", tag)))?;
+        if self.ended {
+            return Err(protocol_error(format!(
+                "{:?} frame after the final frame",
+                kind
+            )));
+        }
+        if self.started == (kind == FrameKind::Start) {
+            return Err(protocol_error(if self.started {
+                "duplicate start frame".to_string()
+            } else {
+                format!("{:?} frame before the start frame", kind)
+            }));
+        }
+
+        let len = u32::from_le_bytes(self.unread()[1..FRAME_HEADER_SIZE].try_into().unwrap());
+        self.offset += FRAME_HEADER_SIZE;
+        Ok((kind, len as usize))
+    }
+
+    fn read_header(&mut self) -> Result<(), ProcessFunError> {
+        let header: [u8; HEADER_SIZE] = self.unread()[..HEADER_SIZE].try_into().unwrap();
+        self.offset += HEADER_SIZE;
+        if header[..4] != MAGIC {
+            return Err(protocol_error(format!(
+                "invalid magic number {:02x?}",
+                &header[..4]
+            )));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(protocol_error(format!(
+                "unsupported protocol version {}, expected {}",
+                version, VERSION
+            )));
+        }
+        let id = header[6];
+        let codec = CodecKind::from_id(id).ok_or_else(|| {
+            protocol_error(format!(
+                "unknown codec {}, the feature enabling it may be missing",
+                id
+            ))
+        })?;
+        if let Some(expected) = self.expected_codec.filter(|&expected| expected != codec) {
+            return Err(protocol_error(format!(
+                "stream is encoded with the {} codec instead of {}",
+                codec.name(),
+                expected.name()
+            )));
+        }
+        self.codec = codec;
+        self.header_read = true;
+        Ok(())
+    }
+
+    /// Check that the stream did not end in the middle of the header or a frame, once all
+    /// complete frames have been taken
+    pub fn finish(&self) -> Result<(), ProcessFunError> {
+        let received = self.unread().len();
+        if let Some((kind, len)) = self.pending {
+            Err(protocol_error(format!(
+                "stream truncated in {:?} frame after {} of {} payload bytes",
+                kind, received, len
+            )))
+        } else if received == 0 {
+            Ok(())
+        } else if !self.header_read {
+            Err(protocol_error(format!(
+                "stream truncated in the header after {} of {} bytes",
+                received, HEADER_SIZE
+            )))
+        } else if received < FRAME_HEADER_SIZE {
+            Err(protocol_error(format!(
+                "stream truncated in a frame header after {} of {} bytes",
+                received, FRAME_HEADER_SIZE
+            )))
+        } else {
+            let tag = self.unread()[0];
+            let len = u32::from_le_bytes(self.unread()[1..FRAME_HEADER_SIZE].try_into().unwrap());
+            let frame = FrameKind::from_tag(tag).map_or_else(
+                || format!("frame {}", tag),
+                |kind| format!("{:?} frame", kind),
+            );
+            Err(protocol_error(format!(
+                "stream truncated in {} after {} of {} payload bytes",
+                frame,
+                received - FRAME_HEADER_SIZE,
+                len
+            )))
+        }
+    }
+}
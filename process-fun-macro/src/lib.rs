@@ -7,24 +7,553 @@
 //! which re-exports these macros in a more convenient way.
 
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use proc_macro_error::{proc_macro_error, Diagnostic, Level};
+use process_fun_core::sys::Signal;
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, spanned::Spanned, ItemFn, PatType, Type};
+use std::time::Duration;
+use syn::meta::ParseNestedMeta;
+use syn::{parse_macro_input, spanned::Spanned, ItemFn, Lit, LitStr, PatType, Type};
+
+/// Options given to the attribute, e.g. `#[process(terminate(signal = "SIGTERM"))]`,
+/// translated into builder calls on `ProcessOptions`
+#[derive(Default)]
+struct ProcessArgs {
+    option_calls: Vec<TokenStream2>,
+    /// Builder calls on the `FsAccess` shared by `fs_read`, `fs_write` and `fs_best_effort`
+    fs_access_calls: Vec<TokenStream2>,
+    stream: bool,
+}
+
+impl ProcessArgs {
+    fn parse(&mut self, meta: ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("stream") {
+            self.stream = true;
+            Ok(())
+        } else if meta.path.is_ident("terminate") {
+            let mut signal = quote!(process_fun::sys::Signal::SIGTERM);
+            let mut grace_period = quote!(process_fun::DEFAULT_GRACE_PERIOD);
+            meta.parse_nested_meta(|meta| {
+                if meta.path.is_ident("signal") {
+                    signal = parse_signal(&meta.value()?.parse()?)?;
+                    Ok(())
+                } else if meta.path.is_ident("grace") {
+                    grace_period = parse_duration(&meta.value()?.parse()?)?;
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `signal` or `grace`"))
+                }
+            })?;
+            self.option_calls.push(quote! {
+                .termination(process_fun::TerminationPolicy::graceful(#signal, #grace_period))
+            });
+            Ok(())
+        } else if meta.path.is_ident("process_group") {
+            let lit: LitStr = meta.value()?.parse()?;
+            let process_group = match lit.value().as_str() {
+                "inherit" => quote!(Inherit),
+                "new" => quote!(New),
+                "session" => quote!(Session),
+                _ => {
+                    return Err(syn::Error::new(
+                        lit.span(),
+                        "expected \"inherit\", \"new\" or \"session\"",
+                    ))
+                }
+            };
+            self.option_calls.push(quote! {
+                .process_group(process_fun::ProcessGroup::#process_group)
+            });
+            Ok(())
+        } else if meta.path.is_ident("parent_death_signal") {
+            let signal = parse_signal(&meta.value()?.parse()?)?;
+            self.option_calls
+                .push(quote!(.parent_death_signal(#signal)));
+            Ok(())
+        } else if meta.path.is_ident("limits") {
+            let mut limit_calls = vec![];
+            meta.parse_nested_meta(|meta| {
+                let lit: Lit = meta.value()?.parse()?;
+                let limit = if meta.path.is_ident("address_space") {
+                    let bytes = parse_size(&lit)?;
+                    quote!(.address_space(#bytes))
+                } else if meta.path.is_ident("cpu_time") {
+                    let cpu_time = match &lit {
+                        Lit::Int(seconds) => {
+                            let seconds: u64 = seconds.base10_parse()?;
+                            quote!(std::time::Duration::from_secs(#seconds))
+                        }
+                        Lit::Str(lit) => parse_duration(lit)?,
+                        _ => return Err(syn::Error::new(lit.span(), "expected a duration")),
+                    };
+                    quote!(.cpu_time(#cpu_time))
+                } else if meta.path.is_ident("nofile") {
+                    let count = parse_count(&lit)?;
+                    quote!(.nofile(#count))
+                } else if meta.path.is_ident("file_size") {
+                    let bytes = parse_size(&lit)?;
+                    quote!(.file_size(#bytes))
+                } else if meta.path.is_ident("nproc") {
+                    let count = parse_count(&lit)?;
+                    quote!(.nproc(#count))
+                } else {
+                    return Err(meta.error(
+                        "expected `address_space`, `cpu_time`, `nofile`, `file_size` or `nproc`",
+                    ));
+                };
+                limit_calls.push(limit);
+                Ok(())
+            })?;
+            self.option_calls.push(quote! {
+                .limits(process_fun::ResourceLimits::new() #(#limit_calls)*)
+            });
+            Ok(())
+        } else if meta.path.is_ident("seccomp") {
+            let filter = if meta.input.peek(syn::Token![=]) {
+                seccomp_preset(&meta.value()?.parse()?)?
+            } else {
+                let mut filter = quote!(process_fun::SeccompFilter::new());
+                let mut allowed = vec![];
+                meta.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("preset") {
+                        filter = seccomp_preset(&meta.value()?.parse()?)?;
+                        Ok(())
+                    } else if meta.path.is_ident("allow") {
+                        allowed.extend(parse_str_array(
+                            &meta.value()?.parse()?,
+                            "expected the name of a system call",
+                        )?);
+                        Ok(())
+                    } else {
+                        Err(meta.error("expected `preset` or `allow`"))
+                    }
+                })?;
+                quote!(#filter #(.allow(#allowed))*)
+            };
+            self.option_calls.push(quote!(.seccomp(#filter)));
+            Ok(())
+        } else if meta.path.is_ident("privileges") {
+            let mut privilege_calls = vec![];
+            meta.parse_nested_meta(|meta| {
+                if meta.path.is_ident("uid") || meta.path.is_ident("gid") {
+                    let id = meta.path.get_ident().unwrap();
+                    let value: u32 = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+                    privilege_calls.push(quote!(.#id(#value)));
+                } else if meta.path.is_ident("groups") {
+                    let groups: syn::ExprArray = meta.value()?.parse()?;
+                    let groups = groups
+                        .elems
+                        .iter()
+                        .map(|group| match group {
+                            syn::Expr::Lit(syn::ExprLit {
+                                lit: Lit::Int(group),
+                                ..
+                            }) => group.base10_parse::<u32>(),
+                            group => Err(syn::Error::new(group.span(), "expected a group ID")),
+                        })
+                        .collect::<syn::Result<Vec<_>>>()?;
+                    privilege_calls.push(quote!(.groups([#(#groups),*])));
+                } else if meta.path.is_ident("drop_capabilities") {
+                    let value = meta.value()?;
+                    if value.peek(LitStr) {
+                        let all: LitStr = value.parse()?;
+                        if all.value() != "all" {
+                            return Err(syn::Error::new(
+                                all.span(),
+                                "expected \"all\" or a list of capabilities",
+                            ));
+                        }
+                        privilege_calls.push(quote!(.drop_all_capabilities()));
+                    } else {
+                        let names = parse_str_array(&value.parse()?, "expected a capability")?;
+                        privilege_calls.push(quote!(#(.drop_capability(#names))*));
+                    }
+                } else if meta.path.is_ident("no_new_privs") {
+                    privilege_calls.push(quote!(.no_new_privs()));
+                } else {
+                    return Err(meta.error(
+                        "expected `uid`, `gid`, `groups`, `drop_capabilities` or `no_new_privs`",
+                    ));
+                }
+                Ok(())
+            })?;
+            self.option_calls.push(quote! {
+                .privileges(process_fun::Privileges::new() #(#privilege_calls)*)
+            });
+            Ok(())
+        } else if meta.path.is_ident("fs_read") || meta.path.is_ident("fs_write") {
+            let (deny, allow) = if meta.path.is_ident("fs_read") {
+                (quote!(.deny_read()), quote!(read))
+            } else {
+                (quote!(.deny_write()), quote!(write))
+            };
+            let paths = parse_str_array(&meta.value()?.parse()?, "expected a path")?;
+            self.fs_access_calls.push(quote!(#deny #(.#allow(#paths))*));
+            Ok(())
+        } else if meta.path.is_ident("fs_best_effort") {
+            self.fs_access_calls.push(quote!(.best_effort()));
+            Ok(())
+        } else if meta.path.is_ident("namespaces") {
+            let mut namespace_calls = vec![];
+            meta.parse_nested_meta(|meta| {
+                let namespace = ["user", "network", "mount", "ipc", "pid"]
+                    .into_iter()
+                    .find(|name| meta.path.is_ident(name))
+                    .ok_or_else(|| {
+                        meta.error("expected `user`, `network`, `mount`, `ipc` or `pid`")
+                    })?;
+                let namespace = format_ident!("{}", namespace);
+                namespace_calls.push(quote!(.#namespace()));
+                Ok(())
+            })?;
+            self.option_calls.push(quote! {
+                .namespaces(process_fun::Namespaces::new() #(#namespace_calls)*)
+            });
+            Ok(())
+        } else if meta.path.is_ident("stdout") || meta.path.is_ident("stderr") {
+            let stream = meta.path.get_ident().cloned();
+            let mode = if meta.input.peek(syn::Token![=]) {
+                let lit: LitStr = meta.value()?.parse()?;
+                match lit.value().as_str() {
+                    "inherit" => quote!(process_fun::OutputMode::Inherit),
+                    "null" => quote!(process_fun::OutputMode::Null),
+                    "capture" => quote!(process_fun::OutputMode::capture()),
+                    _ => {
+                        return Err(syn::Error::new(
+                            lit.span(),
+                            "expected \"inherit\", \"null\" or \"capture\"",
+                        ))
+                    }
+                }
+            } else {
+                let mut mode = None;
+                meta.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("capture") {
+                        let lit: Lit = meta.value()?.parse()?;
+                        let limit = usize::try_from(parse_size(&lit)?)
+                            .map_err(|_| syn::Error::new(lit.span(), "limit is too large"))?;
+                        mode = Some(quote!(process_fun::OutputMode::Capture { limit: #limit }));
+                        Ok(())
+                    } else if meta.path.is_ident("file") {
+                        let path: LitStr = meta.value()?.parse()?;
+                        mode = Some(quote!(process_fun::OutputMode::File(#path.into())));
+                        Ok(())
+                    } else {
+                        Err(meta.error("expected `capture` or `file`"))
+                    }
+                })?;
+                mode.ok_or_else(|| meta.error("expected `capture` or `file`"))?
+            };
+            self.option_calls.push(quote!(.#stream(#mode)));
+            Ok(())
+        } else if meta.path.is_ident("env_clear") {
+            self.option_calls.push(quote!(.env_clear()));
+            Ok(())
+        } else if meta.path.is_ident("env") {
+            meta.parse_nested_meta(|meta| {
+                let key = meta
+                    .path
+                    .get_ident()
+                    .ok_or_else(|| meta.error("expected a variable name"))?
+                    .to_string();
+                let value: LitStr = meta.value()?.parse()?;
+                self.option_calls.push(quote!(.env(#key, #value)));
+                Ok(())
+            })
+        } else if meta.path.is_ident("env_remove") {
+            let keys = parse_str_array(&meta.value()?.parse()?, "expected a variable name")?;
+            self.option_calls.push(quote!(#(.env_remove(#keys))*));
+            Ok(())
+        } else if meta.path.is_ident("current_dir") {
+            let dir: LitStr = meta.value()?.parse()?;
+            self.option_calls.push(quote!(.current_dir(#dir)));
+            Ok(())
+        } else if meta.path.is_ident("umask") {
+            let mask: u32 = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+            self.option_calls.push(quote!(.umask(#mask)));
+            Ok(())
+        } else if meta.path.is_ident("codec") {
+            let lit: LitStr = meta.value()?.parse()?;
+            let codec = match lit.value().as_str() {
+                "bincode" => quote!(Bincode),
+                "postcard" => quote!(Postcard),
+                "msgpack" => quote!(MessagePack),
+                "cbor" => quote!(Cbor),
+                "json" => quote!(Json),
+                _ => {
+                    return Err(syn::Error::new(
+                        lit.span(),
+                        "expected \"bincode\", \"postcard\", \"msgpack\", \"cbor\" or \"json\"",
+                    ))
+                }
+            };
+            // Fails to compile unless the feature of the codec is enabled
+            self.option_calls
+                .push(quote!(.codec(process_fun::CodecKind::#codec)));
+            Ok(())
+        } else if meta.path.is_ident("shared_memory") {
+            let threshold = if meta.input.peek(syn::Token![=]) {
+                let lit: Lit = meta.value()?.parse()?;
+                let threshold = usize::try_from(parse_size(&lit)?)
+                    .map_err(|_| syn::Error::new(lit.span(), "threshold is too large"))?;
+                quote!(#threshold)
+            } else {
+                quote!(process_fun::DEFAULT_SHARED_MEMORY_THRESHOLD)
+            };
+            self.option_calls.push(quote!(.shared_memory(#threshold)));
+            Ok(())
+        } else if meta.path.is_ident("cgroup") {
+            let mut limit_calls = vec![];
+            // A bare `cgroup` only accounts for the child
+            if !meta.input.is_empty() && !meta.input.peek(syn::Token![,]) {
+                meta.parse_nested_meta(|meta| {
+                    let lit: Lit = meta.value()?.parse()?;
+                    let limit = if meta.path.is_ident("memory_max") {
+                        let bytes = parse_size(&lit)?;
+                        quote!(.memory_max(#bytes))
+                    } else if meta.path.is_ident("swap_max") {
+                        let bytes = parse_size(&lit)?;
+                        quote!(.swap_max(#bytes))
+                    } else if meta.path.is_ident("cpu_max") {
+                        let Lit::Str(lit) = &lit else {
+                            return Err(syn::Error::new(lit.span(), "expected \"quota/period\""));
+                        };
+                        let value = lit.value();
+                        let (quota, period) = value.split_once('/').ok_or_else(|| {
+                            syn::Error::new(
+                                lit.span(),
+                                "expected \"quota/period\", e.g. \"50ms/100ms\"",
+                            )
+                        })?;
+                        let quota = parse_duration(&LitStr::new(quota, lit.span()))?;
+                        let period = parse_duration(&LitStr::new(period, lit.span()))?;
+                        quote!(.cpu_max(#quota, #period))
+                    } else if meta.path.is_ident("pids_max") {
+                        let count = parse_count(&lit)?;
+                        quote!(.pids_max(#count))
+                    } else if meta.path.is_ident("parent") {
+                        let Lit::Str(parent) = &lit else {
+                            return Err(syn::Error::new(lit.span(), "expected a path"));
+                        };
+                        quote!(.parent(#parent))
+                    } else {
+                        return Err(meta.error(
+                            "expected `memory_max`, `swap_max`, `cpu_max`, `pids_max` or `parent`",
+                        ));
+                    };
+                    limit_calls.push(limit);
+                    Ok(())
+                })?;
+            }
+            self.option_calls.push(quote! {
+                .cgroup(process_fun::CgroupLimits::new() #(#limit_calls)*)
+            });
+            Ok(())
+        } else if meta.path.is_ident("deadline") {
+            let deadline = parse_duration(&meta.value()?.parse()?)?;
+            self.option_calls.push(quote!(.deadline(#deadline)));
+            Ok(())
+        } else {
+            Err(meta.error("unsupported #[process] option"))
+        }
+    }
+}
+
+/// The item type of an `impl Iterator<Item = T>` (or `impl IntoIterator<Item = T>`) return
+/// type, which makes the function a streaming process function
+fn impl_iterator_item(ty: &Type) -> Option<&Type> {
+    let Type::ImplTrait(impl_trait) = ty else {
+        return None;
+    };
+    impl_trait.bounds.iter().find_map(|bound| {
+        let syn::TypeParamBound::Trait(bound) = bound else {
+            return None;
+        };
+        let segment = bound.path.segments.last()?;
+        if segment.ident != "Iterator" && segment.ident != "IntoIterator" {
+            return None;
+        }
+        let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+        args.args.iter().find_map(|arg| match arg {
+            syn::GenericArgument::AssocType(assoc) if assoc.ident == "Item" => Some(&assoc.ty),
+            _ => None,
+        })
+    })
+}
+
+/// The message types `R` and `S` of a `ProcessChannel<R, S>` parameter type
+fn channel_types(ty: &Type) -> Option<(&Type, &Type)> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "ProcessChannel" {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    let mut types = args.args.iter().filter_map(|arg| match arg {
+        syn::GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    });
+    Some((types.next()?, types.next()?))
+}
+
+/// Parse a signal name such as `"SIGTERM"` into a path to the `Signal` variant
+fn parse_signal(lit: &LitStr) -> syn::Result<TokenStream2> {
+    let signal: Signal = lit
+        .value()
+        .parse()
+        .map_err(|_| syn::Error::new(lit.span(), "unknown signal"))?;
+    let ident = format_ident!("{}", signal.as_str());
+    Ok(quote!(process_fun::sys::Signal::#ident))
+}
+
+/// Parse an array of string literals, e.g. `["openat", "getppid"]`
+fn parse_str_array(array: &syn::ExprArray, expected: &str) -> syn::Result<Vec<LitStr>> {
+    array
+        .elems
+        .iter()
+        .map(|elem| match elem {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: Lit::Str(lit), ..
+            }) => Ok(lit.clone()),
+            elem => Err(syn::Error::new(elem.span(), expected)),
+        })
+        .collect()
+}
+
+/// Parse the name of a seccomp preset into an expression creating its filter
+fn seccomp_preset(lit: &LitStr) -> syn::Result<TokenStream2> {
+    match lit.value().as_str() {
+        "compute_only" => Ok(quote!(process_fun::SeccompFilter::compute_only())),
+        _ => Err(syn::Error::new(
+            lit.span(),
+            "unknown seccomp preset, expected \"compute_only\"",
+        )),
+    }
+}
+
+/// Parse a size such as `"1G"` or a number of bytes
+fn parse_size(lit: &Lit) -> syn::Result<u64> {
+    match lit {
+        Lit::Int(bytes) => bytes.base10_parse(),
+        Lit::Str(size) => {
+            process_fun_core::parse_size(&size.value()).map_err(|e| syn::Error::new(size.span(), e))
+        }
+        _ => Err(syn::Error::new(
+            lit.span(),
+            "expected a size such as \"1G\"",
+        )),
+    }
+}
+
+/// Parse a count given as a number
+fn parse_count(lit: &Lit) -> syn::Result<u64> {
+    match lit {
+        Lit::Int(count) => count.base10_parse(),
+        _ => Err(syn::Error::new(lit.span(), "expected a number")),
+    }
+}
+
+/// Parse a duration such as `"30s"` into a `Duration` expression
+fn parse_duration(lit: &LitStr) -> syn::Result<TokenStream2> {
+    let duration: Duration = process_fun_core::parse_duration(&lit.value())
+        .map_err(|e| syn::Error::new(lit.span(), e))?;
+    let nanos = u64::try_from(duration.as_nanos())
+        .map_err(|_| syn::Error::new(lit.span(), "duration is too long"))?;
+    Ok(quote!(std::time::Duration::from_nanos(#nanos)))
+}
 
 /// Attribute macro that creates an additional version of a function that executes in a separate process.
 ///
 /// When applied to a function named `foo`, this macro:
 /// 1. Keeps the original function unchanged, allowing normal in-process calls
 /// 2. Creates a new function named `foo_process` that returns a ProcessWrapper
+/// 3. Creates a function named `foo_process_with` taking a `ProcessOptions` after the
+///    arguments, which is run with those options instead of the ones given in the attribute
 ///
 /// # Requirements
 ///
 /// The function must:
 /// * Have arguments and return type that implement `Serialize` and `Deserialize`
 ///
+/// # Channels
+///
+/// A function may take one `ProcessChannel<R, S>` parameter to exchange messages with the
+/// parent while it runs. `foo_process` does not take that parameter and returns the parent's
+/// end, a `ProcessChannel<S, R>`, next to the process.
+///
+/// # Options
+///
+/// * `stream` - The function returns an iterator whose items are sent to the parent as soon as
+///   they are produced, and `foo_process` returns a `ProcessStream` yielding them. Functions
+///   returning `impl Iterator<Item = T>` are streaming without this option.
+/// * `terminate(signal = "SIGTERM", grace = "5s")` - Terminate the child with `signal` when it
+///   is aborted, times out or its wrapper is dropped, and kill it with `SIGKILL` if it is still
+///   running after the grace period. `signal` defaults to `SIGTERM` and `grace` to
+///   `DEFAULT_GRACE_PERIOD`, five seconds. Without this option the child is killed with
+///   `SIGKILL` right away.
+/// * `process_group = "new" | "inherit" | "session"` - Which process group the child is placed in.
+///   By default it leads a new process group, so it is terminated together with everything it
+///   spawned.
+/// * `parent_death_signal = "SIGKILL"` - Signal sent to the child when its parent dies.
+/// * `deadline = "10m"` - The child kills itself once this much time has passed, even if no
+///   parent is left to enforce a timeout.
+/// * `limits(address_space = "1G", cpu_time = "30s", nofile = 256, file_size = "100M", nproc = 64)` -
+///   Resource limits applied with `setrlimit` before the function runs. Exceeding the CPU time
+///   or file size limit is reported as `CpuLimitExceeded` or `FileSizeLimitExceeded`.
+/// * `privileges(uid = 1000, gid = 1000, groups = [100], drop_capabilities = ["net_raw"], no_new_privs)` -
+///   Switch the user and groups of the child and drop capabilities (or all of them with
+///   `drop_capabilities = "all"`) before the function runs. A step that fails is reported as an
+///   error instead of running the function.
+/// * `seccomp = "compute_only"` or `seccomp(preset = "compute_only", allow = ["openat"])` -
+///   Only allow the system calls of the preset and the `allow` list once the child is set up.
+///   `compute_only` reads and writes no file descriptors but those of the call, such as its
+///   channel. A forbidden system call is reported as `SandboxViolation`.
+/// * `fs_read = ["/data"]`, `fs_write = ["/tmp/out"]` - Only allow reading, or writing, beneath
+///   these paths once the child is set up, using Landlock. Paths allowed for writing may also
+///   be read. If the kernel does not support Landlock, the call fails with
+///   `LandlockUnsupported`, or runs unrestricted with `fs_best_effort`.
+/// * `namespaces(user, network, mount, ipc, pid)` - Isolate the child in new Linux namespaces.
+///   A user namespace is also entered when the others cannot be created without one. With
+///   `pid`, the function runs as PID 1 of the new namespace.
+/// * `stdout = "inherit" | "null" | "capture"`, `stdout(capture = "64K")` or
+///   `stdout(file = "out.log")`, and the same for `stderr` - Where the output of the child
+///   goes. Captured output is kept in memory up to the given size, 1M by default, and returned
+///   by `wait_with_output`. A file is created or truncated before the function runs.
+/// * `env_clear`, `env(RUST_LOG = "debug")`, `env_remove = ["HOME"]` - Change the environment
+///   of the child, applied in this order. `env_clear` starts from an empty environment.
+/// * `current_dir = "/tmp"` - The current directory of the child.
+/// * `umask = 0o077` - The file mode creation mask of the child.
+/// * `codec = "bincode" | "postcard" | "msgpack" | "cbor" | "json"` - The codec serializing the
+///   values exchanged with the child, which requires the feature of the same name. By default
+///   the codec set with `codec::set_default` is used, bincode unless changed.
+/// * `shared_memory` or `shared_memory = "64M"` - Transfer results whose serialized size reaches
+///   the threshold, 1M by default, through a sealed memfd instead of the pipe. The parent maps
+///   it and deserializes the result without copying it first.
+/// * `cgroup(memory_max = "256M", swap_max = 0, cpu_max = "50ms/100ms", pids_max = 64, parent = "/sys/fs/cgroup/app")` -
+///   Place the child in its own cgroup v2 with these limits (requires the `cgroup` feature).
+///   A bare `cgroup` places it in a cgroup without limits, only for accounting.
+///   Being killed for exceeding `memory_max` is reported as `OutOfMemory`.
+///
 #[proc_macro_error]
 #[proc_macro_attribute]
-pub fn process(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn process(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = ProcessArgs::default();
+    let args_parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with args_parser);
+    if !args.fs_access_calls.is_empty() {
+        let fs_access_calls = &args.fs_access_calls;
+        args.option_calls.push(quote! {
+            .fs_access(process_fun::FsAccess::new() #(#fs_access_calls)*)
+        });
+    }
+    let option_calls = &args.option_calls;
+
     let input_fn = parse_macro_input!(item as ItemFn);
 
     // Check for duplicate process attributes
@@ -40,7 +569,28 @@ pub fn process(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     let fn_name = &input_fn.sig.ident;
     let process_fn_name = format_ident!("{}_process", fn_name);
+    let process_with_fn_name = format_ident!("{}_process_with", fn_name);
     let fn_args = &input_fn.sig.inputs;
+
+    // A channel parameter is connected to the parent instead of being passed by the caller
+    let mut channel = None;
+    let mut process_fn_args = vec![];
+    for arg in fn_args.iter() {
+        match arg {
+            syn::FnArg::Typed(PatType { pat, ty, .. }) if channel_types(ty).is_some() => {
+                if channel.is_some() {
+                    return syn::Error::new(
+                        arg.span(),
+                        "only one ProcessChannel parameter is allowed",
+                    )
+                    .to_compile_error()
+                    .into();
+                }
+                channel = Some((pat, ty, channel_types(ty).unwrap()));
+            }
+            arg => process_fn_args.push(arg),
+        }
+    }
     let generics = &input_fn.sig.generics;
     let (_impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
@@ -106,22 +656,108 @@ pub fn process(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let args_types_tuple = quote! { (#(#arg_types),*) };
     let fn_name_str = fn_name.to_string();
 
+    // `foo_process` runs `foo_process_with` with the options of the attribute
+    let process_arg_names = process_fn_args.iter().filter_map(|arg| match arg {
+        syn::FnArg::Typed(PatType { pat, .. }) => match &**pat {
+            syn::Pat::Ident(pat_ident) => Some(&pat_ident.ident),
+            _ => panic!("Unsupported argument pattern"),
+        },
+        syn::FnArg::Receiver(_) => None,
+    });
+    let options = quote!(process_fun::ProcessOptions::new() #(#option_calls)*);
+    let call_process_with = if self_stream {
+        quote!(self.#process_with_fn_name(#(#process_arg_names,)* #options))
+    } else {
+        quote!(#process_with_fn_name(#(#process_arg_names,)* #options))
+    };
+
     let call = if self_stream {
         quote!(self.#fn_name(#(#arg_names),*))
     } else {
         quote!(#fn_name(#(#arg_names),*))
     };
 
+    // Streaming functions send items instead of a single result
+    let stream_item = match &input_fn.sig.output {
+        syn::ReturnType::Type(_, ty) => match impl_iterator_item(ty) {
+            Some(item) => Some(quote!(#item)),
+            None if args.stream => Some(quote!(<#ty as IntoIterator>::Item)),
+            None => None,
+        },
+        syn::ReturnType::Default if args.stream => {
+            return syn::Error::new(
+                input_fn.sig.span(),
+                "#[process(stream)] requires a function returning an iterator",
+            )
+            .to_compile_error()
+            .into();
+        }
+        syn::ReturnType::Default => None,
+    };
+    let (process_output, process_type, result) = match &stream_item {
+        Some(item) => (
+            quote!(process_fun::ProcessStream<#item>),
+            quote!(process_fun::ProcessStream),
+            quote! {
+                setup
+                    .and_then(|_| process_fun::catch_panic(|| process_fun::send_items(#call)))
+                    .and_then(|sent| sent)
+            },
+        ),
+        None => (
+            quote!(process_fun::ProcessWrapper<#fn_output>),
+            quote!(process_fun::ProcessWrapper),
+            quote!(setup.and_then(|_| process_fun::catch_panic(|| #call))),
+        ),
+    };
+
+    let (process_output, create_channel, parent_result, drop_parent_channel, use_channel) =
+        match &channel {
+            Some((pat, ty, (received, sent))) => {
+                let syn::Pat::Ident(pat_ident) = &***pat else {
+                    panic!("Unsupported argument pattern")
+                };
+                let ident = &pat_ident.ident;
+                (
+                    quote!((#process_output, process_fun::ProcessChannel<#sent, #received>)),
+                    quote! {
+                        let (#pat, parent_channel): (#ty, _) = process_fun::ProcessChannel::pair_with_options(&options)?;
+                    },
+                    quote! {{
+                        std::mem::drop(#ident);
+                        (process, parent_channel)
+                    }},
+                    quote!(std::mem::drop(parent_channel);),
+                    quote!(#ident.use_in_child();),
+                )
+            }
+            None => (
+                process_output,
+                quote!(),
+                quote!(process),
+                quote!(),
+                quote!(),
+            ),
+        };
+
     let expanded = quote! {
         #input_fn
 
-        #[allow(non_snake_case)]
-        pub fn #process_fn_name #ty_generics(#fn_args) -> Result<process_fun::ProcessWrapper<#fn_output>, process_fun::ProcessFunError> #where_clause {
-            // Create pipes for result and start time communication
+        #[allow(non_snake_case, unused_mut)]
+        pub fn #process_fn_name #ty_generics(#(#process_fn_args),*) -> Result<#process_output, process_fun::ProcessFunError> #where_clause {
+            #call_process_with
+        }
+
+        #[allow(non_snake_case, unused_mut)]
+        pub fn #process_with_fn_name #ty_generics(#(#process_fn_args,)* options: process_fun::ProcessOptions) -> Result<#process_output, process_fun::ProcessFunError> #where_clause {
+            // Create the pipe carrying the frames sent by the child
             #[cfg(feature = "debug")]
             eprintln!("[process-fun-debug] Creating pipes for process function: {}", #fn_name_str);
 
             let (mut read_pipe, mut write_pipe) = process_fun::create_pipes()?;
+            let output = process_fun::OutputPipes::new(&options)?;
+            let shared_memory = process_fun::SharedMemory::new(&options)?;
+            #create_channel
 
             // Fork the process
             #[cfg(feature = "debug")]
@@ -132,19 +768,33 @@ pub fn process(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     std::mem::drop(write_pipe);
 
                     // Create ProcessWrapper with child pid and receiver
-                    Ok(process_fun::ProcessWrapper::new(child, read_pipe))
+                    let process = #process_type::with_options(child, read_pipe, &options)
+                        .with_output(output)?
+                        .with_shared_memory(shared_memory);
+                    Ok(#parent_result)
                 }
                 process_fun::sys::ForkResult::Child => {
                     // Child process - close read ends immediately
                     std::mem::drop(read_pipe);
+                    #drop_parent_channel
 
                     #[cfg(feature = "debug")]
                     eprintln!("[process-fun-debug] Child process started");
 
-                    // Get and send start time by stating the child process
-                    let pid = process_fun::sys::getpid();
-                    let start_time = process_fun::stat_pid_start(pid)?;
-                    process_fun::write_time(&mut write_pipe, start_time)?;
+                    // Set up the child before the start time is sent, failures are reported
+                    // in place of the result
+                    let setup = process_fun::setup_child(&options).and_then(|_| output.redirect());
+
+                    // Send the stream header and start time, which identifies the child if
+                    // pidfds are not supported. The child must never return into the caller's code.
+                    if process_fun::start_child(write_pipe, &options).is_err() {
+                        std::process::exit(1);
+                    }
+                    shared_memory.use_in_child();
+                    #use_channel
+
+                    // Restrict the child once the handshake is done
+                    let setup = setup.and_then(|_| process_fun::restrict_child(&options));
 
                     #[cfg(feature = "debug")]
                     {
@@ -152,21 +802,12 @@ pub fn process(_attr: TokenStream, item: TokenStream) -> TokenStream {
                         eprintln!("[process-fun-debug] Arguments tuple type: {}", stringify!(#args_types_tuple));
                     }
 
-                    // Execute the function with the original arguments
-                    let result = #call;
-
-                    #[cfg(feature = "debug")]
-                    eprintln!("[process-fun-debug] Child process result: {:?}", &result);
-
-                    // Serialize and write result
-                    let result_bytes = process_fun::ser::to_vec(&result)?;
-                    process_fun::write_to_pipe(write_pipe, &result_bytes)?;
-
-                    #[cfg(feature = "debug")]
-                    eprintln!("[process-fun-debug] Child process completed");
+                    // Execute the function with the original arguments, catching a panic so its
+                    // details can be reported to the parent instead of a broken pipe
+                    let result = #result;
 
-                    // Exit child process
-                    std::process::exit(0);
+                    // Send the result and exit the child process
+                    process_fun::finish_child(result)
                 }
             }
         }
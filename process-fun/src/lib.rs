@@ -20,7 +20,9 @@
 //!    - Safe result deserialization
 //!
 //! This execution model ensures complete isolation between the parent and child processes,
-//! making it suitable for running potentially risky or resource-intensive operations.
+//! making it suitable for running potentially risky or resource-intensive operations. The child
+//! still has the full system call surface of the parent, unless it is restricted with
+//! `#[process(seccomp = "compute_only")]` or a custom allow-list.
 //!
 //! ## Usage
 //!
@@ -153,7 +155,112 @@ mod tests {
     #[test]
     fn test_process_panic() {
         let result = panicking_function_process().unwrap().wait();
-        assert!(result.is_err(), "Expected error due to panic");
+        match result {
+            Err(ProcessFunError::ChildPanicked {
+                message, location, ..
+            }) => {
+                assert_eq!(message, "This function panics!");
+                assert!(location.unwrap().contains("lib.rs"));
+            }
+            other => panic!("Expected ChildPanicked error, got {:?}", other),
+        }
+    }
+
+    #[process]
+    fn exiting_function(code: i32) -> i32 {
+        std::process::exit(code);
+    }
+
+    #[test]
+    fn test_process_exit_code() {
+        let mut process = exiting_function_process(3).unwrap();
+        let result = process.wait();
+        assert!(
+            matches!(result, Err(ProcessFunError::ChildExited { code: 3 })),
+            "{:?}",
+            result
+        );
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Exited(_, 3))
+        ));
+    }
+
+    #[process]
+    fn aborting_function() -> i32 {
+        std::process::abort();
+    }
+
+    #[test]
+    fn test_process_signaled() {
+        let result = aborting_function_process().unwrap().wait();
+        match result {
+            Err(ProcessFunError::ChildSignaled { signal, .. }) => {
+                assert_eq!(signal, sys::Signal::SIGABRT as i32)
+            }
+            other => panic!("Expected ChildSignaled error, got {:?}", other),
+        }
+    }
+
+    /// Whether `pid` is a zombie child of this process
+    fn is_zombie_child(pid: sys::Pid) -> bool {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            return false;
+        };
+        // The command name may contain spaces, so parse the fields after its closing paren
+        let mut fields = stat[stat.rfind(')').unwrap() + 2..].split(' ');
+        let state = fields.next().unwrap();
+        let ppid: i32 = fields.next().unwrap().parse().unwrap();
+        state == "Z" && ppid == std::process::id() as i32
+    }
+
+    #[process]
+    fn quick_function(value: u32) -> u32 {
+        value + 1
+    }
+
+    #[test]
+    fn test_no_zombies() {
+        let mut pids = vec![];
+        for i in 0..50 {
+            // Waited on
+            let mut process = quick_function_process(i).unwrap();
+            assert_eq!(process.wait().unwrap(), i + 1);
+            pids.push(process.pid());
+
+            // Dropped without waiting
+            let process = quick_function_process(i).unwrap();
+            pids.push(process.pid());
+        }
+        let zombies: Vec<_> = pids.into_iter().filter(|&p| is_zombie_child(p)).collect();
+        assert!(zombies.is_empty(), "Zombie children left: {:?}", zombies);
+    }
+
+    #[test]
+    fn test_reaper_collects_forgotten_children() {
+        reaper::install().unwrap();
+        assert!(reaper::is_active());
+
+        let mut pids = vec![];
+        for i in 0..50 {
+            let process = quick_function_process(i).unwrap();
+            pids.push(process.pid());
+            std::mem::forget(process);
+        }
+
+        let start = std::time::Instant::now();
+        loop {
+            let zombies: Vec<_> = pids.iter().filter(|&&p| is_zombie_child(p)).collect();
+            if zombies.is_empty() {
+                break;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Zombie children left: {:?}",
+                zombies
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
     }
 
     #[process]
@@ -170,6 +277,861 @@ mod tests {
         assert_eq!(result.unwrap(), 42);
     }
 
+    #[test]
+    fn test_try_wait() {
+        let mut processes: Vec<_> = (0..5)
+            .map(|_| slow_but_within_timeout_process().unwrap())
+            .collect();
+
+        // The children sleep, so none of them is done right away
+        for process in processes.iter_mut() {
+            assert!(process.try_wait().unwrap().is_none());
+        }
+
+        let start = std::time::Instant::now();
+        let mut results = vec![];
+        while !processes.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Processes hang");
+            processes.retain_mut(|process| match process.try_wait().unwrap() {
+                Some(result) => {
+                    results.push(result);
+                    false
+                }
+                None => true,
+            });
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(results, vec![42; 5]);
+    }
+
+    /// Fork a child that sends a header with a wrong magic number and keeps running for
+    /// `duration` afterwards
+    fn corrupt_stream_process(duration: Duration) -> ProcessWrapper<i32> {
+        let (receiver, mut sender) = create_pipes().unwrap();
+        match fork_process().unwrap() {
+            sys::ForkResult::Parent { child } => ProcessWrapper::new(child, receiver),
+            sys::ForkResult::Child => {
+                let _ = std::io::Write::write_all(&mut sender, &[b'X'; wire::HEADER_SIZE]);
+                thread::sleep(duration);
+                std::process::exit(0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_wait_corrupt_stream() {
+        let mut process = corrupt_stream_process(Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(100));
+
+        // The protocol error does not block until the child exits
+        let start = std::time::Instant::now();
+        assert!(process.try_wait().unwrap().is_none());
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let result = loop {
+            assert!(start.elapsed() < Duration::from_secs(5), "Process hangs");
+            match process.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(10)),
+                result => break result,
+            }
+        };
+        match result {
+            Err(ProcessFunError::ProtocolError(message)) => {
+                assert!(message.contains("magic number"), "{}", message)
+            }
+            other => panic!("Expected ProtocolError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wait_timeout_keeps_process() {
+        let mut process = slow_but_within_timeout_process().unwrap();
+
+        // The child is left running when the timeout expires
+        let result = process.wait_timeout(Duration::from_millis(50)).unwrap();
+        assert!(result.is_none());
+        assert!(process.status().is_none());
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let result = process.wait_deadline(deadline).unwrap();
+        assert_eq!(result, Some(42));
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Exited(_, 0))
+        ));
+    }
+
+    #[process(terminate(signal = "SIGTERM", grace = "5s"))]
+    fn sleep_until_terminated() -> i32 {
+        thread::sleep(Duration::from_secs(10));
+        0
+    }
+
+    #[test]
+    fn test_graceful_termination() {
+        let mut process = sleep_until_terminated_process().unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(process.abort().unwrap(), Termination::Graceful);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Signaled(_, sys::Signal::SIGTERM, _))
+        ));
+
+        // Without `grace`, the child still gets time to clean up
+        let mut process = clean_up_on_sigterm_process().unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(process.abort().unwrap(), Termination::Graceful);
+    }
+
+    static SIGTERM_RECEIVED: std::sync::atomic::AtomicBool =
+        std::sync::atomic::AtomicBool::new(false);
+
+    extern "C" fn on_sigterm(_: i32) {
+        SIGTERM_RECEIVED.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[process(terminate(signal = "SIGTERM"))]
+    fn clean_up_on_sigterm() -> i32 {
+        unsafe {
+            sys::signal::signal(
+                sys::Signal::SIGTERM,
+                sys::signal::SigHandler::Handler(on_sigterm),
+            )
+            .unwrap();
+        }
+        while !SIGTERM_RECEIVED.load(std::sync::atomic::Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Cleaning up takes a moment
+        thread::sleep(Duration::from_millis(100));
+        0
+    }
+
+    #[process]
+    fn ignore_sigterm() -> i32 {
+        unsafe {
+            sys::signal::signal(sys::Signal::SIGTERM, sys::signal::SigHandler::SigIgn).unwrap();
+        }
+        thread::sleep(Duration::from_secs(10));
+        0
+    }
+
+    #[test]
+    fn test_termination_escalates_to_sigkill() {
+        let policy = TerminationPolicy::graceful(sys::Signal::SIGTERM, Duration::from_millis(200));
+        let mut process = ignore_sigterm_process()
+            .unwrap()
+            .with_termination_policy(policy);
+
+        // Give the child time to ignore SIGTERM
+        assert!(process
+            .wait_timeout(Duration::from_millis(200))
+            .unwrap()
+            .is_none());
+
+        let result = process.timeout(Duration::from_millis(10));
+        assert!(matches!(result, Err(ProcessFunError::TimeoutError)));
+        assert_eq!(process.termination(), Some(Termination::Killed));
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Signaled(_, sys::Signal::SIGKILL, _))
+        ));
+    }
+
+    /// Whether `pid` has exited (it may linger as a zombie of its new parent)
+    fn is_dead(pid: i32) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => {
+                let state = stat[stat.rfind(')').unwrap() + 2..].split(' ').next();
+                matches!(state, Some("Z") | Some("X"))
+            }
+            Err(_) => true,
+        }
+    }
+
+    #[process]
+    fn sleep_forever() -> i32 {
+        thread::sleep(Duration::from_secs(60));
+        0
+    }
+
+    #[process]
+    fn own_start_time() -> u64 {
+        pid_start_time(sys::getpid()).unwrap()
+    }
+
+    #[test]
+    fn test_child_start_time() {
+        let parent_start = pid_start_time(sys::getpid()).unwrap();
+        assert_eq!(pid_start_time(sys::getpid()).unwrap(), parent_start);
+
+        // A child cannot have started before its parent
+        let mut process = sleep_forever_process().unwrap();
+        let child_start = pid_start_time(process.pid()).unwrap();
+        assert!(child_start >= parent_start);
+        process.abort().unwrap();
+
+        let child_start = own_start_time_process().unwrap().wait().unwrap();
+        assert!(child_start >= parent_start);
+    }
+
+    #[process]
+    fn spawn_nested(pid_file: std::path::PathBuf) -> i32 {
+        let nested = sleep_forever_process().unwrap();
+        fs::write(&pid_file, nested.pid().to_string()).unwrap();
+        thread::sleep(Duration::from_secs(60));
+        drop(nested);
+        0
+    }
+
+    #[test]
+    fn test_abort_kills_nested_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("nested.pid");
+
+        let mut process = spawn_nested_process(pid_file.clone()).unwrap();
+        let start = std::time::Instant::now();
+        let nested_pid = loop {
+            if let Ok(pid) = fs::read_to_string(&pid_file) {
+                if let Ok(pid) = pid.parse::<i32>() {
+                    break pid;
+                }
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Nested process missing"
+            );
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(!is_dead(nested_pid));
+
+        process.abort().unwrap();
+
+        // The nested process is killed along with its parent, but reaped by init
+        let start = std::time::Instant::now();
+        while !is_dead(nested_pid) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Nested process survived"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[process(parent_death_signal = "SIGKILL")]
+    fn sleep_until_orphaned() -> i32 {
+        thread::sleep(Duration::from_secs(60));
+        0
+    }
+
+    #[process(process_group = "inherit")]
+    fn spawn_and_exit(pid_file: std::path::PathBuf) -> i32 {
+        let nested = sleep_until_orphaned_process().unwrap();
+        fs::write(&pid_file, nested.pid().to_string()).unwrap();
+        // Exit without running destructors, as if the process crashed
+        std::process::exit(0);
+    }
+
+    #[test]
+    fn test_parent_death_signal() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("nested.pid");
+
+        let result = spawn_and_exit_process(pid_file.clone()).unwrap().wait();
+        assert!(matches!(
+            result,
+            Err(ProcessFunError::ChildExited { code: 0 })
+        ));
+
+        let nested_pid: i32 = fs::read_to_string(&pid_file).unwrap().parse().unwrap();
+        let start = std::time::Instant::now();
+        while !is_dead(nested_pid) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Orphaned process survived"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[process(deadline = "200ms")]
+    fn exceed_deadline() -> i32 {
+        thread::sleep(Duration::from_secs(10));
+        0
+    }
+
+    #[test]
+    fn test_child_deadline() {
+        let start = std::time::Instant::now();
+        let result = exceed_deadline_process().unwrap().wait();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match result {
+            Err(ProcessFunError::ChildSignaled { signal, .. }) => {
+                assert_eq!(signal, sys::Signal::SIGKILL as i32)
+            }
+            other => panic!("Expected ChildSignaled error, got {:?}", other),
+        }
+
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(
+            parse_duration("999999999999999999h"),
+            Err("Duration '999999999999999999h' is too large".to_string())
+        );
+    }
+
+    #[process(limits(cpu_time = 1))]
+    fn spin_forever() -> u64 {
+        let mut n = 0u64;
+        loop {
+            n = std::hint::black_box(n.wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn test_cpu_time_limit() {
+        let result = spin_forever_process().unwrap().wait();
+        assert!(matches!(result, Err(ProcessFunError::CpuLimitExceeded)));
+    }
+
+    #[process(limits(file_size = "1K"))]
+    fn write_large_file(path: std::path::PathBuf) -> bool {
+        fs::write(path, vec![0u8; 4096]).is_ok()
+    }
+
+    #[test]
+    fn test_file_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = write_large_file_process(dir.path().join("large"))
+            .unwrap()
+            .wait();
+        assert!(matches!(
+            result,
+            Err(ProcessFunError::FileSizeLimitExceeded)
+        ));
+    }
+
+    #[process(limits(nofile = 16))]
+    fn open_many_files(path: std::path::PathBuf) -> usize {
+        let files: Vec<_> = (0..32).map_while(|_| fs::File::open(&path).ok()).collect();
+        files.len()
+    }
+
+    #[test]
+    fn test_nofile_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let opened = open_many_files_process(dir.path().to_path_buf())
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(opened < 16);
+    }
+
+    #[cfg(feature = "cgroup")]
+    #[process(cgroup)]
+    fn spawn_and_report_cgroup() -> (String, u32) {
+        let cgroup = fs::read_to_string("/proc/self/cgroup").unwrap();
+        // Left behind on purpose, to be killed with the cgroup
+        #[allow(clippy::zombie_processes)]
+        let sleeper = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .unwrap();
+        (cgroup, sleeper.id())
+    }
+
+    #[cfg(feature = "cgroup")]
+    #[test]
+    #[ignore = "needs a writable cgroup v2 hierarchy"]
+    fn test_cgroup_placement() {
+        CgroupLimits::new().check().unwrap();
+
+        let mut process = spawn_and_report_cgroup_process().unwrap();
+        let path = process.cgroup_path().unwrap().to_path_buf();
+        let pid = process.pid();
+        let (cgroup, sleeper) = process.wait().unwrap();
+
+        let own = cgroup.lines().find(|line| line.starts_with("0::")).unwrap();
+        assert!(own.ends_with(&format!("/process-fun-{}", pid)));
+        assert!(process.cgroup_stats().is_some());
+
+        // The cgroup is removed, killing the subprocess left behind in it
+        assert!(process.cgroup_path().is_none());
+        assert!(!path.exists());
+        let start = std::time::Instant::now();
+        while !is_dead(sleeper as i32) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "Subprocess survived"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(feature = "cgroup")]
+    #[process(cgroup(memory_max = "32M", swap_max = 0))]
+    fn allocate_too_much() -> usize {
+        let chunks: Vec<Vec<u8>> = (0..256).map(|_| vec![1u8; 1 << 20]).collect();
+        chunks.len()
+    }
+
+    #[cfg(feature = "cgroup")]
+    #[test]
+    #[ignore = "needs a writable cgroup v2 hierarchy with the memory controller"]
+    fn test_cgroup_out_of_memory() {
+        CgroupLimits::new()
+            .memory_max(32 << 20)
+            .swap_max(0)
+            .check()
+            .unwrap();
+
+        // Under a delegated cgroup, the test process is moved into a leaf to enable the memory
+        // controller
+        let mut process = allocate_too_much_process().unwrap();
+        let result = process.wait();
+        assert!(
+            matches!(result, Err(ProcessFunError::OutOfMemory)),
+            "{:?}",
+            result
+        );
+        let events = process.cgroup_stats().unwrap().memory_events.unwrap();
+        assert!(events.oom_kill > 0);
+    }
+
+    #[process(seccomp = "compute_only")]
+    fn sandboxed_sum(n: u64) -> u64 {
+        let numbers: Vec<u64> = (0..n).collect();
+        numbers.iter().sum()
+    }
+
+    #[process(seccomp = "compute_only")]
+    fn sandboxed_open() -> bool {
+        fs::File::open("/etc/hostname").is_ok()
+    }
+
+    #[process(seccomp(preset = "compute_only", allow = ["getppid"]))]
+    fn sandboxed_getppid() -> i32 {
+        nix::unistd::getppid().as_raw()
+    }
+
+    #[process(seccomp = "compute_only")]
+    fn sandboxed_print(stderr: bool) -> bool {
+        use std::io::Write;
+        match stderr {
+            true => std::io::stderr().write_all(b"sandboxed\n").is_ok(),
+            false => std::io::stdout().write_all(b"sandboxed\n").is_ok(),
+        }
+    }
+
+    #[process(seccomp = "compute_only")]
+    fn sandboxed_double(mut channel: ProcessChannel<u32, u32>) -> u32 {
+        let number = channel.recv().unwrap();
+        channel.send(&(number * 2)).unwrap();
+        number
+    }
+
+    #[process(seccomp = "compute_only", deadline = "200ms")]
+    fn sandboxed_sleep() -> i32 {
+        thread::sleep(Duration::from_secs(10));
+        0
+    }
+
+    #[test]
+    fn test_seccomp() {
+        // Allocating a large vector needs mmap
+        let result = sandboxed_sum_process(1 << 20).unwrap().wait().unwrap();
+        assert_eq!(result, (0..1u64 << 20).sum::<u64>());
+
+        match sandboxed_open_process().unwrap().wait() {
+            Err(ProcessFunError::SandboxViolation { syscall }) => {
+                assert!(syscall == "openat" || syscall == "open", "{}", syscall)
+            }
+            other => panic!("Expected SandboxViolation error, got {:?}", other),
+        }
+
+        // Only the pipe, shared memory and channel of the child may be written, and standard
+        // error for panic messages
+        assert!(sandboxed_print_process(true).unwrap().wait().unwrap());
+        match sandboxed_print_process(false).unwrap().wait() {
+            Err(ProcessFunError::SandboxViolation { syscall }) => assert_eq!(syscall, "write"),
+            other => panic!("Expected SandboxViolation error, got {:?}", other),
+        }
+
+        let (mut process, mut channel) = sandboxed_double_process().unwrap();
+        channel.send(&21).unwrap();
+        assert_eq!(channel.recv().unwrap(), 42);
+        assert_eq!(process.wait().unwrap(), 21);
+
+        let parent = sandboxed_getppid_process().unwrap().wait().unwrap();
+        assert_eq!(parent, std::process::id() as i32);
+
+        // The deadline watchdog may still kill the child
+        match sandboxed_sleep_process().unwrap().wait() {
+            Err(ProcessFunError::ChildSignaled { signal, .. }) => {
+                assert_eq!(signal, sys::Signal::SIGKILL as i32)
+            }
+            other => panic!("Expected ChildSignaled error, got {:?}", other),
+        }
+    }
+
+    #[process(namespaces(network))]
+    fn isolated_connect(port: u16) -> bool {
+        std::net::TcpStream::connect(("127.0.0.1", port)).is_ok()
+    }
+
+    #[process(namespaces(user))]
+    fn isolated_uid_map() -> String {
+        fs::read_to_string("/proc/self/uid_map").unwrap()
+    }
+
+    #[process(namespaces(mount, pid))]
+    fn isolated_processes() -> (u32, usize) {
+        let processes = fs::read_dir("/proc")
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().parse::<u32>().is_ok()
+            })
+            .count();
+        (std::process::id(), processes)
+    }
+
+    #[process(namespaces(pid))]
+    fn isolated_panic() -> i32 {
+        panic!("Panicking as PID 1");
+    }
+
+    #[process(namespaces(pid))]
+    fn isolated_sleep() -> i32 {
+        thread::sleep(Duration::from_secs(10));
+        0
+    }
+
+    /// The result of an isolated child, or `None` if namespaces cannot be created here
+    fn unless_unavailable<T>(
+        result: Result<T, ProcessFunError>,
+    ) -> Option<Result<T, ProcessFunError>> {
+        match result {
+            Err(ProcessFunError::ProcessError(message)) => {
+                eprintln!("Skipping, namespaces are unavailable: {}", message);
+                None
+            }
+            result => Some(result),
+        }
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        if let Some(result) = unless_unavailable(isolated_connect_process(port).unwrap().wait()) {
+            assert!(!result.unwrap());
+        }
+
+        // Only the user of the parent is mapped
+        if let Some(result) = unless_unavailable(isolated_uid_map_process().unwrap().wait()) {
+            let map = result.unwrap();
+            let fields: Vec<_> = map.split_whitespace().collect();
+            assert_eq!(fields.len(), 3, "{}", map);
+            assert_eq!(fields[2], "1");
+        }
+
+        // The function is PID 1 and alone in its namespace, while the parent still identifies
+        // the process it forked
+        let mut process = isolated_processes_process().unwrap();
+        assert!(process.pid().as_raw() > 1);
+        if let Some(result) = unless_unavailable(process.wait()) {
+            assert_eq!(result.unwrap(), (1, 1));
+        }
+
+        if let Some(result) = unless_unavailable(isolated_panic_process().unwrap().wait()) {
+            match result {
+                Err(ProcessFunError::ChildPanicked { message, .. }) => {
+                    assert!(message.contains("Panicking as PID 1"))
+                }
+                other => panic!("Expected ChildPanicked error, got {:?}", other),
+            }
+        }
+
+        let mut process = isolated_sleep_process().unwrap();
+        let start = std::time::Instant::now();
+        if let Some(result) = unless_unavailable(process.timeout(Duration::from_millis(500))) {
+            assert!(matches!(result, Err(ProcessFunError::TimeoutError)));
+            assert!(start.elapsed() < Duration::from_secs(5));
+            assert!(matches!(
+                process.status(),
+                Some(sys::WaitStatus::Signaled(_, sys::Signal::SIGKILL, _))
+            ));
+        }
+    }
+
+    const LANDLOCK_DIR: &str = "/tmp/process-fun-landlock";
+
+    #[process(
+        fs_read = ["/tmp/process-fun-landlock/readable"],
+        fs_write = ["/tmp/process-fun-landlock/writable"]
+    )]
+    fn restricted_fs() -> Vec<bool> {
+        vec![
+            fs::read_to_string(format!("{}/readable/input", LANDLOCK_DIR)).is_ok(),
+            fs::write(format!("{}/writable/output", LANDLOCK_DIR), "written").is_ok(),
+            fs::read_to_string(format!("{}/other/input", LANDLOCK_DIR)).is_ok(),
+            fs::write(format!("{}/readable/output", LANDLOCK_DIR), "written").is_ok(),
+            fs::write(format!("{}/other/output", LANDLOCK_DIR), "written").is_ok(),
+        ]
+    }
+
+    #[process(fs_write = ["/tmp/process-fun-landlock/writable"])]
+    fn restricted_writes() -> Vec<bool> {
+        vec![
+            fs::read_to_string(format!("{}/other/input", LANDLOCK_DIR)).is_ok(),
+            fs::create_dir(format!("{}/writable/created", LANDLOCK_DIR)).is_ok(),
+            fs::remove_file(format!("{}/other/input", LANDLOCK_DIR)).is_ok(),
+        ]
+    }
+
+    #[test]
+    fn test_landlock() {
+        let _ = fs::remove_dir_all(LANDLOCK_DIR);
+        for dir in ["readable", "writable", "other"] {
+            fs::create_dir_all(format!("{}/{}", LANDLOCK_DIR, dir)).unwrap();
+            fs::write(format!("{}/{}/input", LANDLOCK_DIR, dir), "input").unwrap();
+        }
+
+        let allowed = match restricted_fs_process().unwrap().wait() {
+            Err(ProcessFunError::LandlockUnsupported) => {
+                eprintln!("Skipping, the kernel does not support Landlock");
+                return;
+            }
+            result => result.unwrap(),
+        };
+        assert_eq!(allowed, [true, true, false, false, false]);
+
+        // Only writing is restricted
+        let allowed = restricted_writes_process().unwrap().wait().unwrap();
+        assert_eq!(allowed, [true, true, false]);
+
+        // The parent is unaffected
+        fs::write(format!("{}/other/output", LANDLOCK_DIR), "written").unwrap();
+        fs::remove_dir_all(LANDLOCK_DIR).unwrap();
+    }
+
+    /// A field of `/proc/self/status`, e.g. `Uid`
+    fn status_field(name: &str) -> String {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .unwrap()
+            .trim()
+            .to_string()
+    }
+
+    fn capability_set(name: &str) -> u64 {
+        u64::from_str_radix(&status_field(name), 16).unwrap()
+    }
+
+    #[process(privileges(uid = 65534, gid = 65534, drop_capabilities = "all", no_new_privs))]
+    fn unprivileged_status() -> Vec<String> {
+        ["Uid", "Gid", "Groups", "NoNewPrivs"]
+            .map(status_field)
+            .to_vec()
+    }
+
+    #[process(privileges(drop_capabilities = ["CAP_NET_RAW", "chown"]))]
+    fn without_net_raw() -> (u64, u64) {
+        (capability_set("CapEff"), capability_set("CapBnd"))
+    }
+
+    #[process(privileges(drop_capabilities = ["not_a_capability"]))]
+    fn with_unknown_capability() -> bool {
+        true
+    }
+
+    #[test]
+    fn test_privileges() {
+        // Failures are reported instead of running the function
+        match with_unknown_capability_process().unwrap().wait() {
+            Err(ProcessFunError::ProcessError(message)) => {
+                assert!(message.contains("not_a_capability"), "{}", message)
+            }
+            other => panic!("Expected ProcessError, got {:?}", other),
+        }
+
+        // CAP_SETGID, CAP_SETUID and CAP_SETPCAP
+        let needed = (1 << 6) | (1 << 7) | (1 << 8);
+        if capability_set("CapEff") & needed != needed {
+            assert!(matches!(
+                unprivileged_status_process().unwrap().wait(),
+                Err(ProcessFunError::ProcessError(_))
+            ));
+            return;
+        }
+
+        let status = unprivileged_status_process().unwrap().wait().unwrap();
+        assert_eq!(
+            status,
+            [
+                "65534\t65534\t65534\t65534",
+                "65534\t65534\t65534\t65534",
+                "",
+                "1"
+            ]
+        );
+
+        let (effective, bounding) = without_net_raw_process().unwrap().wait().unwrap();
+        let dropped = (1 << 13) | 1;
+        assert_eq!(effective & dropped, 0);
+        assert_eq!(bounding & dropped, 0);
+        assert_eq!(effective, capability_set("CapEff") & !dropped);
+    }
+
+    #[process]
+    fn spin_and_allocate(megabytes: usize) -> u64 {
+        let memory = vec![1u8; megabytes << 20];
+        let start = std::time::Instant::now();
+        let mut n = 0u64;
+        while start.elapsed() < Duration::from_millis(300) {
+            n = std::hint::black_box(n.wrapping_add(1));
+        }
+        n + memory.iter().map(|&byte| byte as u64).sum::<u64>()
+    }
+
+    #[test]
+    fn test_report() {
+        let mut process = spin_and_allocate_process(64).unwrap();
+        let (result, report) = process.wait_with_report();
+        assert!(result.is_ok());
+        let report = report.unwrap();
+        assert_eq!(process.report(), Some(&report));
+
+        assert!(matches!(report.exit_status, sys::WaitStatus::Exited(_, 0)));
+        assert!(report.wall_time >= Duration::from_millis(300));
+        // Other tests compete for the CPU
+        assert!(report.user_time + report.sys_time > Duration::ZERO);
+        assert!(report.user_time + report.sys_time <= report.wall_time);
+        assert!(report.max_rss >= 64 << 20, "{:?}", report);
+        assert!(report.minor_faults > 0);
+
+        // Failed calls are reported as well
+        let (result, report) = panicking_function_process().unwrap().wait_with_report();
+        assert!(result.is_err());
+        assert!(matches!(
+            report.unwrap().exit_status,
+            sys::WaitStatus::Exited(_, _)
+        ));
+    }
+
+    // The test harness captures `print!` in the child as well, so the streams are written to
+    // directly
+    #[process(stdout = "capture", stderr(capture = 16))]
+    fn chatty(lines: usize) -> usize {
+        use std::io::Write;
+        // Without a newline, so it is only written when stdout is flushed on exit
+        write!(std::io::stdout(), "result: {}", lines).unwrap();
+        for line in 0..lines {
+            writeln!(std::io::stderr(), "line {}", line).unwrap();
+        }
+        lines
+    }
+
+    #[process(stdout(file = "/tmp/process-fun-stdout.txt"), stderr = "null")]
+    fn chatty_to_file() -> bool {
+        use std::io::Write;
+        writeln!(std::io::stdout(), "to the file").unwrap();
+        writeln!(std::io::stderr(), "to nowhere").unwrap();
+        true
+    }
+
+    #[test]
+    fn test_output() {
+        let output = chatty_process(3).unwrap().wait_with_output();
+        assert_eq!(output.result.unwrap(), 3);
+        assert_eq!(output.stdout.bytes, b"result: 3");
+        assert!(!output.stdout.truncated);
+        assert_eq!(output.stderr.bytes, b"line 0\nline 1\nli");
+        assert!(output.stderr.truncated);
+
+        // More than fits into the pipe, which must be drained while the child runs
+        let output = chatty_process(100_000).unwrap().wait_with_output();
+        assert_eq!(output.result.unwrap(), 100_000);
+        assert_eq!(output.stderr.bytes.len(), 16);
+
+        let mut process = chatty_to_file_process().unwrap();
+        assert!(process.wait().unwrap());
+        let (stdout, stderr) = process.take_output().unwrap();
+        assert!(stdout.bytes.is_empty() && stderr.bytes.is_empty());
+        assert_eq!(
+            fs::read_to_string("/tmp/process-fun-stdout.txt").unwrap(),
+            "to the file\n"
+        );
+        let _ = fs::remove_file("/tmp/process-fun-stdout.txt");
+    }
+
+    #[process(
+        env_clear,
+        env(PROCESS_FUN_TEST = "set"),
+        current_dir = "/tmp",
+        umask = 0o027
+    )]
+    fn environment() -> (Vec<(String, String)>, String, String) {
+        (
+            std::env::vars().collect(),
+            std::env::current_dir().unwrap().display().to_string(),
+            status_field("Umask"),
+        )
+    }
+
+    #[test]
+    fn test_environment() {
+        let (vars, dir, umask) = environment_process().unwrap().wait().unwrap();
+        assert_eq!(vars, [("PROCESS_FUN_TEST".to_string(), "set".to_string())]);
+        assert_eq!(dir, "/tmp");
+        assert_eq!(umask, "0027");
+
+        // The options given at runtime replace those of the attribute
+        let options = ProcessOptions::new()
+            .env("PROCESS_FUN_TEST", "runtime")
+            .env_remove("PATH")
+            .current_dir("/");
+        let (vars, dir, _) = environment_process_with(options).unwrap().wait().unwrap();
+        assert!(vars.contains(&("PROCESS_FUN_TEST".to_string(), "runtime".to_string())));
+        assert!(vars.iter().all(|(key, _)| key != "PATH"));
+        assert!(std::env::var_os("PATH").is_some());
+        assert_eq!(dir, "/");
+
+        let options = ProcessOptions::new().current_dir("/nonexistent");
+        match environment_process_with(options).unwrap().wait() {
+            Err(ProcessFunError::ProcessError(message)) => {
+                assert!(message.contains("/nonexistent"), "{}", message)
+            }
+            other => panic!("Expected ProcessError, got {:?}", other),
+        }
+    }
+
+    #[process(shared_memory = "1M", seccomp = "compute_only")]
+    fn large_buffer(megabytes: usize) -> Vec<u8> {
+        (0..megabytes << 20).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_shared_memory() {
+        let expected = large_buffer(8);
+        assert_eq!(large_buffer_process(8).unwrap().wait().unwrap(), expected);
+
+        // Below the threshold, and when the file size limit does not allow the memfd, the
+        // result goes through the pipe
+        assert_eq!(
+            large_buffer_process(0).unwrap().wait().unwrap(),
+            Vec::<u8>::new()
+        );
+        let options = ProcessOptions::new()
+            .shared_memory(1024)
+            .limits(ResourceLimits::new().file_size(1024));
+        let result = large_buffer_process_with(8, options).unwrap().wait();
+        assert_eq!(result.unwrap(), expected);
+    }
+
     #[process]
     fn write_file_slow() -> bool {
         // Try to write to a file after sleeping
@@ -186,8 +1148,12 @@ mod tests {
         let mut process = write_file_slow_process().unwrap();
         let result = process.timeout(Duration::from_millis(500));
 
-        // Should timeout
-        assert!(result.is_err());
+        // Should timeout, and the killed child should have been reaped
+        assert!(matches!(result, Err(ProcessFunError::TimeoutError)));
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Signaled(_, sys::Signal::SIGKILL, _))
+        ));
 
         // Give a small grace period for the filesystem
         thread::sleep(Duration::from_secs(5));
@@ -232,4 +1198,334 @@ mod tests {
         let expected = long_calculation(iterations);
         assert_eq!(result.unwrap(), expected);
     }
+
+    #[process]
+    fn double_numbers(mut channel: ProcessChannel<u32, u32>, limit: u32) -> u32 {
+        let mut received = 0;
+        // Runs until the parent closes its end
+        while let Ok(number) = channel.recv() {
+            received += 1;
+            channel.send(&(number * 2).min(limit)).unwrap();
+        }
+        received
+    }
+
+    #[test]
+    fn test_channel() {
+        let (mut process, mut channel) = double_numbers_process(5).unwrap();
+        assert!(channel.try_recv().unwrap().is_none());
+
+        for number in 1..=3 {
+            channel.send(&number).unwrap();
+            assert_eq!(channel.recv().unwrap(), (number * 2).min(5));
+        }
+        assert!(channel
+            .recv_timeout(Duration::from_millis(50))
+            .unwrap()
+            .is_none());
+
+        drop(channel);
+        assert_eq!(process.wait().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_channel_closed_when_child_exits() {
+        let (mut process, mut channel) = double_numbers_process(5).unwrap();
+        process.abort().unwrap();
+        assert!(matches!(
+            channel.recv(),
+            Err(ProcessFunError::ChannelClosed)
+        ));
+        assert!(matches!(
+            channel.send(&1),
+            Err(ProcessFunError::ChannelClosed)
+        ));
+    }
+
+    #[process]
+    fn report_progress(steps: u32) -> u32 {
+        for step in 1..=steps {
+            thread::sleep(Duration::from_millis(50));
+            progress::report(&(step as f32 / steps as f32)).unwrap();
+        }
+        steps
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut process = report_progress_process(5).unwrap();
+        let mut updates = vec![];
+        let result = loop {
+            if let Some(result) = process.wait_timeout(Duration::from_millis(20)).unwrap() {
+                break result;
+            }
+            updates.extend(process.progress::<f32>().map(Result::unwrap));
+        };
+        assert_eq!(result, 5);
+
+        // Updates received together with the result are still available
+        updates.extend(process.progress::<f32>().map(Result::unwrap));
+        assert_eq!(updates, [0.2, 0.4, 0.6, 0.8, 1.0]);
+
+        // Reporting does nothing when the function is called directly
+        assert_eq!(report_progress(2), 2);
+    }
+
+    #[process]
+    fn count_slowly(n: u32, delay: Duration) -> impl Iterator<Item = u32> {
+        (0..n).inspect(move |_| thread::sleep(delay))
+    }
+
+    #[process(stream)]
+    fn collected_words(text: String) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[process]
+    fn count_then_panic(n: u32) -> impl Iterator<Item = u32> {
+        (0..=n).map(move |i| if i < n { i } else { panic!("Stream failed") })
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = count_slowly_process(5, Duration::from_millis(200)).unwrap();
+
+        // The first item arrives long before the child produced all of them
+        let start = std::time::Instant::now();
+        assert_eq!(stream.next().unwrap().unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_millis(600));
+
+        let rest: Vec<_> = stream.by_ref().map(Result::unwrap).collect();
+        assert_eq!(rest, [1, 2, 3, 4]);
+        assert!(stream.next().is_none());
+        assert!(matches!(
+            stream.status(),
+            Some(sys::WaitStatus::Exited(_, 0))
+        ));
+
+        let words = collected_words_process("a stream of words".to_string()).unwrap();
+        let words: Vec<_> = words.map(Result::unwrap).collect();
+        assert_eq!(words, ["a", "stream", "of", "words"]);
+    }
+
+    #[test]
+    fn test_stream_ends_with_error() {
+        let items: Vec<_> = count_then_panic_process(3).unwrap().collect();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[2].as_ref().unwrap(), &2);
+        match &items[3] {
+            Err(ProcessFunError::ChildPanicked { message, .. }) => {
+                assert_eq!(message, "Stream failed")
+            }
+            other => panic!("Expected ChildPanicked error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_dropping_stream_kills_child() {
+        let mut stream = count_slowly_process(u32::MAX, Duration::from_millis(10)).unwrap();
+        let pid = stream.pid();
+        let first: Vec<_> = stream.by_ref().take(3).map(Result::unwrap).collect();
+        assert_eq!(first, [0, 1, 2]);
+
+        drop(stream);
+        assert!(is_dead(pid.as_raw()));
+    }
+
+    /// Decode a complete stream, returning its frames or the protocol error
+    fn decode_stream(bytes: &[u8]) -> Result<Vec<wire::Frame>, ProcessFunError> {
+        let mut decoder = wire::FrameDecoder::new();
+        let mut frames = vec![];
+        // Feed the bytes one at a time to check that frames split across reads are handled
+        for byte in bytes {
+            decoder.push(&[*byte]);
+            while let Some(frame) = decoder.next_frame()? {
+                frames.push(frame);
+            }
+        }
+        decoder.finish()?;
+        Ok(frames)
+    }
+
+    #[test]
+    fn test_wire_protocol() {
+        let mut writer = wire::FrameWriter::start(Vec::new()).unwrap();
+        writer.write_log("working").unwrap();
+        writer.write_outcome(&Ok(Point { x: 1, y: 2 })).unwrap();
+        let stream = writer.into_inner();
+
+        let frames = decode_stream(&stream).unwrap();
+        let kinds: Vec<_> = frames.iter().map(|frame| frame.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                wire::FrameKind::Start,
+                wire::FrameKind::Log,
+                wire::FrameKind::Result
+            ]
+        );
+        assert_eq!(
+            frames[0].start_time().unwrap(),
+            pid_start_time(sys::getpid()).unwrap()
+        );
+        let point: Point = frames[2].clone().into_outcome().unwrap();
+        assert_eq!((point.x, point.y), (1, 2));
+
+        let protocol_error = |bytes: &[u8]| match decode_stream(bytes) {
+            Err(ProcessFunError::ProtocolError(message)) => message,
+            other => panic!("Expected ProtocolError, got {:?}", other),
+        };
+
+        // Truncated in the middle of the result
+        let message = protocol_error(&stream[..stream.len() - 3]);
+        assert!(message.contains("truncated in Result frame"), "{}", message);
+
+        // Corrupted header and frame tag
+        let mut corrupted = stream.clone();
+        corrupted[0] = b'X';
+        assert!(protocol_error(&corrupted).contains("magic number"));
+        let mut corrupted = stream.clone();
+        corrupted[wire::HEADER_SIZE] = 42;
+        assert!(protocol_error(&corrupted).contains("unknown frame tag 42"));
+
+        // Frames after the result
+        let mut writer = wire::FrameWriter::start(Vec::new()).unwrap();
+        writer.write_outcome(&Ok(0)).unwrap();
+        writer.write_log("too late").unwrap();
+        let message = protocol_error(&writer.into_inner());
+        assert!(message.contains("after the final frame"), "{}", message);
+
+        // Many frames received in a single read
+        let mut writer = wire::FrameWriter::start(Vec::new()).unwrap();
+        for i in 0..1000 {
+            writer.write_log(&i.to_string()).unwrap();
+        }
+        writer.write_outcome(&Ok(0)).unwrap();
+        let mut decoder = wire::FrameDecoder::new();
+        decoder.push(&writer.into_inner());
+        let mut logs = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            if frame.kind == wire::FrameKind::Log {
+                logs.push(String::from_utf8(frame.payload).unwrap());
+            }
+        }
+        decoder.finish().unwrap();
+        assert_eq!(logs, (0..1000).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Labeled {
+        pub label: String,
+        #[serde(flatten)]
+        pub values: std::collections::BTreeMap<String, i32>,
+    }
+
+    // bincode cannot deserialize flattened fields
+    #[cfg(feature = "json")]
+    #[process(codec = "json")]
+    fn labeled(label: String) -> Labeled {
+        Labeled {
+            label,
+            values: [("x".to_string(), 1)].into(),
+        }
+    }
+
+    #[test]
+    fn test_codecs() {
+        for &codec in CodecKind::ALL {
+            let options = ProcessOptions::new().codec(codec);
+            let p1 = Point { x: 1, y: 2 };
+            let p2 = Point { x: 3, y: 4 };
+            let result = add_points_process_with(p1, p2, options.clone())
+                .unwrap()
+                .wait();
+            let result = result.unwrap_or_else(|e| panic!("{}: {:?}", codec.name(), e));
+            assert_eq!((result.x, result.y), (4, 6));
+
+            let result = panicking_function_process_with(options).unwrap().wait();
+            assert!(
+                matches!(result, Err(ProcessFunError::ChildPanicked { .. })),
+                "{}: {:?}",
+                codec.name(),
+                result
+            );
+        }
+
+        // A stream recording another codec than expected is rejected
+        let mut writer =
+            wire::FrameWriter::start_with_codec(Vec::new(), CodecKind::Bincode).unwrap();
+        writer.write_outcome(&Ok(0)).unwrap();
+        let mut stream = writer.into_inner();
+        stream[6] = 4;
+        let mut decoder = wire::FrameDecoder::new().expect_codec(CodecKind::Bincode);
+        decoder.push(&stream);
+        match decoder.next_frame() {
+            Err(ProcessFunError::ProtocolError(message)) => {
+                assert!(message.contains("codec"), "{}", message)
+            }
+            other => panic!("Expected ProtocolError, got {:?}", other),
+        }
+
+        #[cfg(feature = "json")]
+        {
+            let result = labeled_process("point".to_string())
+                .unwrap()
+                .wait()
+                .unwrap();
+            assert_eq!(result, labeled("point".to_string()));
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_wait() {
+        let p1 = Point { x: 1, y: 2 };
+        let p2 = Point { x: 3, y: 4 };
+
+        let result = add_points_process(p1, p2).unwrap().await.unwrap();
+        assert_eq!(result.x, 4);
+        assert_eq!(result.y, 6);
+
+        let result = panicking_function_process().unwrap().await;
+        assert!(matches!(result, Err(ProcessFunError::ChildPanicked { .. })));
+
+        // The protocol error is kept while waiting for the child to exit
+        let result = corrupt_stream_process(Duration::from_millis(200)).await;
+        match result {
+            Err(ProcessFunError::ProtocolError(message)) => {
+                assert!(message.contains("magic number"), "{}", message)
+            }
+            other => panic!("Expected ProtocolError, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_timeout() {
+        let mut process = slow_but_within_timeout_process().unwrap();
+        let result = process.timeout_async(Duration::from_secs(2)).await;
+        assert_eq!(result.unwrap(), 42);
+
+        let mut process = slow_but_within_timeout_process().unwrap();
+        let result = process.timeout_async(Duration::from_millis(100)).await;
+        assert!(matches!(result, Err(ProcessFunError::TimeoutError)));
+        assert!(matches!(
+            process.status(),
+            Some(sys::WaitStatus::Signaled(_, sys::Signal::SIGKILL, _))
+        ));
+    }
+
+    #[cfg(all(feature = "async-io", not(feature = "tokio")))]
+    #[test]
+    fn test_async_io_wait() {
+        futures_lite::future::block_on(async {
+            let result = long_calculation_process(1000).unwrap().await;
+            assert_eq!(result.unwrap(), long_calculation(1000));
+
+            let mut process = slow_but_within_timeout_process().unwrap();
+            let result = process.timeout_async(Duration::from_millis(100)).await;
+            assert!(matches!(result, Err(ProcessFunError::TimeoutError)));
+        });
+    }
 }